@@ -0,0 +1,83 @@
+// Splats mass and momentum onto the grid through the halos, against how p2g1 used to do it by
+// locking the neighbouring chunk for every node that landed outside its own chunk
+use ampm::{MaterialId, Particle, World, solver::splat_momentum, world::{Activity, Chunk}};
+use bevy::math::{IVec3, Vec3A};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rayon::prelude::*;
+
+// A cube of water side nodes wide, 8 particles a cell
+fn block(side: usize) -> World {
+    let mut world = World::new();
+    for x in 0..side * 2 {
+        for y in 0..side * 2 {
+            for z in 0..side * 2 {
+                world.add_particle(Particle::new(Vec3A::new(x as f32, y as f32, z as f32) * 0.5 + 0.25, 0.5, MaterialId::water));
+            }
+        }
+    }
+    world.pick_levels(0.4, 0.5, &[], &[]);
+    world.schedule_tick(0.4);
+    world
+}
+
+// The old p2g1, 27 batches of chunks that are far enough apart not to share any nodes
+fn splat_locked(world: &World) {
+    for n in 0..27 {
+        world.chunks.par_iter().for_each(|(&i, c)| {
+            let ch = c.lock().unwrap();
+            let batch = World::chunk_coords(i).rem_euclid(IVec3::splat(3));
+            if ch.activity < Activity::Splat || Chunk::get_index(3, batch.x, batch.y, batch.z) != n {
+                return;
+            }
+            drop(ch);
+
+            let nbh = world.get_surrounding_chunks(i);
+            let mut locked_chunk = c.lock().unwrap();
+            let Chunk { nodes, particles, .. } = &mut *locked_chunk;
+            for p in particles.iter() {
+                let ogn_coord = p.x.floor();
+                let ogn_diff = (p.x - ogn_coord) - 0.5;
+                let weights = [0.5 * (0.5 - ogn_diff) * (0.5 - ogn_diff), 0.75 - ogn_diff * ogn_diff, 0.5 * (0.5 + ogn_diff) * (0.5 + ogn_diff)];
+                for gx in 0..3 {
+                    for gy in 0..3 {
+                        for gz in 0..3 {
+                            let weight = weights[gx].x * weights[gy].y * weights[gz].z;
+                            let rn_coord = ogn_coord.as_ivec3() + IVec3::new(gx as i32 - 1, gy as i32 - 1, gz as i32 - 1);
+                            let node_dist = (rn_coord.as_vec3a() - p.x) + 0.5;
+                            let m_contrib = weight * p.m;
+                            let momentum = m_contrib * (p.v + p.C * node_dist);
+
+                            let rc_coord = rn_coord.div_euclid(IVec3::splat(Chunk::width as i32));
+                            let n_index = Chunk::node_index(rn_coord - rc_coord * Chunk::width as i32);
+                            if rc_coord == IVec3::ZERO {
+                                nodes[n_index].m += m_contrib;
+                                nodes[n_index].v += momentum;
+                            }
+                            else if let Some(outside) = nbh.get(rc_coord) {
+                                let mut outside = outside.lock().unwrap();
+                                outside.nodes[n_index].m += m_contrib;
+                                outside.nodes[n_index].v += momentum;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn scatter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scatter");
+    group.sample_size(10);
+    // 8, 64 and 216 chunks with particles
+    for side in [16, 32, 48] {
+        let world = block(side);
+        let chunks = (side / Chunk::width).pow(3);
+        group.bench_with_input(BenchmarkId::new("locked", chunks), &world, |b, world| b.iter(|| splat_locked(world)));
+        group.bench_with_input(BenchmarkId::new("halo", chunks), &world, |b, world| b.iter(|| splat_momentum(world)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scatter);
+criterion_main!(benches);
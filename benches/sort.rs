@@ -0,0 +1,50 @@
+// Steps a block of water whose particles were added in random order, with and without sorting
+// them by node, to see what the order of the particles does to the transfers
+use ampm::{MaterialId, Particle, SimParams, Simulation, World};
+use bevy::math::{IVec3, Vec3A};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rand::{seq::SliceRandom, SeedableRng, rngs::StdRng};
+
+// side^3 cells of water with 8 particles each, spread over a domain twice as wide
+fn scrambled_world(side: usize) -> World {
+    let mut world = World::with_bounds(IVec3::ZERO, IVec3::splat(side as i32 * 2 + 8));
+    let mut positions = vec![];
+    for x in 0..side * 2 {
+        for y in 0..side * 2 {
+            for z in 0..side * 2 {
+                positions.push(Vec3A::new(x as f32, y as f32, z as f32) * 0.5 + 4.25);
+            }
+        }
+    }
+    positions.shuffle(&mut StdRng::seed_from_u64(7));
+    for x in positions {
+        world.add_particle(Particle::new(x, 0.5, MaterialId::water));
+    }
+    world
+}
+
+fn sort(c: &mut Criterion) {
+    c.bench_function("sort_particles", |b| {
+        b.iter_batched(|| scrambled_world(24), |mut world| world.sort_particles(), BatchSize::LargeInput)
+    });
+}
+
+fn step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("step");
+    group.sample_size(10);
+    for sort_every in [0, 1, 4] {
+        group.bench_with_input(BenchmarkId::new("sort_every", sort_every), &sort_every, |b, &sort_every| {
+            b.iter_batched(|| {
+                let mut sim = Simulation::from_world(scrambled_world(24));
+                sim.app.insert_resource(SimParams { sort_every, ..Default::default() });
+                // Startup and the first sorts happen outside the measurement
+                sim.run(4);
+                sim
+            }, |mut sim| sim.run(4), BatchSize::PerIteration)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sort, step);
+criterion_main!(benches);
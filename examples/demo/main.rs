@@ -0,0 +1,95 @@
+#![allow(non_upper_case_globals, non_snake_case, dead_code)]
+use ampm::{Colliders, Geometry, Materials, MpmPlugin, MpmRigidBody, SceneFile, SimParams, Simulation, World};
+use bevy::{prelude::*, diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin}, math::Vec3A};
+
+mod cam;
+
+// cargo run --example demo -- --scene scenes/splash.ron --headless --frames 100 --params assets/params.ron
+// Params in the scene file win over --params
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let arg = |name: &str| args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1));
+    let params = match arg("--params") {
+        Some(path) => SimParams::load(path).unwrap_or_else(|e| panic!("{e}")),
+        None => SimParams::default(),
+    };
+    let scene = SceneFile(arg("--scene").map_or("scenes/cube.ron".into(), |s| s.into()));
+    if args.iter().any(|a| a == "--headless") {
+        let frames = arg("--frames")
+            .map(|s| s.parse::<usize>().expect("--frames takes a number"))
+            .unwrap_or(100);
+        run_headless(frames, params, scene);
+        return;
+    }
+
+    App::new()
+        .add_plugins(DefaultPlugins)
+        // World Inspector Menu
+        //.add_plugin(WorldInspectorPlugin::new())
+         // Framerate logging
+        .add_plugins((
+                LogDiagnosticsPlugin::default(), 
+                FrameTimeDiagnosticsPlugin,
+                cam::PlayerPlugin,
+                MpmPlugin,
+                ))
+        .insert_resource(params)
+        .insert_resource(scene)
+        .insert_resource(ClearColor(Color::rgb(1., 1., 1.)))
+        .add_systems(Update, draw.after(ampm::MpmStep))
+        .run();
+}
+
+fn run_headless(frames: usize, params: SimParams, scene: SceneFile) {
+    let mut sim = Simulation::new();
+    sim.app.insert_resource(params).insert_resource(scene);
+    sim.run(frames);
+
+    let particles = sim.world().particles();
+    let center = particles.iter().fold(Vec3A::ZERO, |acc, p| acc + p.x) / particles.len().max(1) as f32;
+    println!("{} frames, {} particles, {} chunks, center of mass {:?}", sim.frames(), particles.len(), sim.world().chunks.len(), center);
+}
+
+fn draw(
+    mut gizmos: Gizmos,
+    world: Res<World>,
+    materials: Res<Materials>,
+    colliders: Res<Colliders>,
+    bodies: Query<(&MpmRigidBody, &Transform)>,
+) {
+    for c in &colliders.0 {
+        draw_geometry(&mut gizmos, &c.geometry, c.position.into(), c.rotation, Color::DARK_GRAY);
+    }
+    for (body, transform) in &bodies {
+        draw_geometry(&mut gizmos, &body.geometry, transform.translation, transform.rotation, Color::ORANGE_RED);
+    }
+    world.chunks.values().for_each(|c| {
+        let chunk = c.lock().unwrap();
+        let offset = chunk.pos.as_vec3();
+        for particle in &chunk.particles {
+            gizmos.sphere(offset + Vec3::from(particle.x), Quat::IDENTITY, 0.25, materials.color(particle.material));
+        }
+    });
+}
+
+// Planes and meshes don't have an outline worth drawing
+fn draw_geometry(gizmos: &mut Gizmos, geometry: &Geometry, position: Vec3, rotation: Quat, color: Color) {
+    match geometry {
+        Geometry::Box { half_extents } => {
+            let transform = Transform::from_translation(position).with_rotation(rotation).with_scale(2. * Vec3::from(*half_extents));
+            gizmos.cuboid(transform, color);
+        }
+        Geometry::Sphere { radius } => {
+            gizmos.sphere(position, rotation, *radius, color);
+        }
+        Geometry::Capsule { half_height, radius } => {
+            let axis = rotation * Vec3::Y * *half_height;
+            gizmos.sphere(position + axis, rotation, *radius, color);
+            gizmos.sphere(position - axis, rotation, *radius, color);
+            gizmos.line(position - axis, position + axis, color);
+        }
+        Geometry::Plane { .. } | Geometry::Mesh(_) => {}
+    }
+}
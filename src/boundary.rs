@@ -0,0 +1,228 @@
+use bevy::{prelude::*, math::Vec3A};
+use serde::{Deserialize, Serialize};
+use crate::{collider::Boundary, world::Chunk};
+
+/// What one face of the domain does to whatever reaches it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Wall {
+    Sticky,
+    Slip,
+    /// Slides with Coulomb friction and lets go of anything moving away from it
+    Separate { friction: f32 },
+    /// Comes back in through the face across from it, which has to be periodic too
+    Periodic,
+    /// Lets everything through, particles that get past it are deleted
+    Outflow,
+}
+
+impl Wall {
+    /// The condition on the grid for the walls that stop things
+    pub fn solid(self) -> Option<Boundary> {
+        match self {
+            Wall::Sticky => Some(Boundary::Sticky),
+            Wall::Slip => Some(Boundary::Slip),
+            Wall::Separate { friction } => Some(Boundary::Separate { friction }),
+            Wall::Periodic | Wall::Outflow => None,
+        }
+    }
+}
+
+/// The six faces of the domain, min has the faces at the low end of x, y and z and max the ones
+/// at the high end
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DomainBoundary {
+    pub min: [Wall; 3],
+    pub max: [Wall; 3],
+}
+
+impl Default for DomainBoundary {
+    fn default() -> Self {
+        DomainBoundary::all(Wall::Slip)
+    }
+}
+
+impl DomainBoundary {
+    pub fn all(wall: Wall) -> Self {
+        DomainBoundary { min: [wall; 3], max: [wall; 3] }
+    }
+
+    /// Both faces across an axis, 0 is x
+    pub fn with_axis(mut self, axis: usize, wall: Wall) -> Self {
+        self.min[axis] = wall;
+        self.max[axis] = wall;
+        self
+    }
+
+    pub fn is_periodic(&self, axis: usize) -> bool {
+        self.min[axis] == Wall::Periodic
+    }
+
+    /// Periodic axes wrap whole chunks, so they need to start on a chunk and be a whole number
+    /// of chunks long
+    pub fn validate(&self, min: IVec3, max: IVec3) -> anyhow::Result<()> {
+        let width = Chunk::width as i32;
+        for axis in 0..3 {
+            let name = ["x", "y", "z"][axis];
+            anyhow::ensure!(min[axis] < max[axis], "domain is empty along {name}");
+            anyhow::ensure!(
+                (self.min[axis] == Wall::Periodic) == (self.max[axis] == Wall::Periodic),
+                "only one face along {name} is periodic"
+            );
+            for wall in [self.min[axis], self.max[axis]] {
+                if let Wall::Separate { friction } = wall {
+                    anyhow::ensure!(friction >= 0., "friction can't be negative, got {friction}");
+                }
+            }
+            if self.is_periodic(axis) {
+                anyhow::ensure!(
+                    min[axis] % width == 0 && (max[axis] - min[axis]) % width == 0,
+                    "periodic along {name} needs the domain to start and end on a multiple of {width}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Velocity of the node at pos after the solid faces it's in, nodes within 2 of a face are
+    /// in its wall
+    pub fn collide(&self, (min, max): (IVec3, IVec3), pos: IVec3, mut v: Vec3A) -> Vec3A {
+        for axis in 0..3 {
+            let n = Vec3A::AXES[axis];
+            if pos[axis] < min[axis] + 2 {
+                if let Some(boundary) = self.min[axis].solid() {
+                    v = boundary.apply(v, n);
+                }
+            }
+            if pos[axis] > max[axis] - 3 {
+                if let Some(boundary) = self.max[axis].solid() {
+                    v = boundary.apply(v, -n);
+                }
+            }
+        }
+        v
+    }
+
+    /// How much to change the velocity of a particle at x so that it'd still be out of the solid
+    /// walls after another step at it, the push counts as going into the wall so friction and
+    /// sticky walls slow the sliding too
+    pub fn push_back(&self, (min, max): (IVec3, IVec3), x: Vec3A, v: Vec3A) -> Vec3A {
+        let x_n = x + v;
+        let wall_min = min.as_vec3a() + 2.;
+        let wall_max = max.as_vec3a() - 3.;
+        let mut push = Vec3A::ZERO;
+        for axis in 0..3 {
+            let faces = [(self.min[axis], wall_min[axis] - x_n[axis], 1.), (self.max[axis], x_n[axis] - wall_max[axis], -1.)];
+            for (wall, depth, sign) in faces {
+                let Some(boundary) = wall.solid() else { continue };
+                if depth <= 0. {
+                    continue;
+                }
+                let n = Vec3A::AXES[axis] * sign;
+                let tangent = v - v.dot(n) * n;
+                push += boundary.apply(tangent - depth * n, n) - tangent + depth * n;
+            }
+        }
+        push
+    }
+
+    /// True if x got out of the domain through an outflow face
+    pub fn outflows(&self, (min, max): (IVec3, IVec3), x: Vec3A) -> bool {
+        (0..3).any(|axis| {
+            (x[axis] < min[axis] as f32 && self.min[axis] == Wall::Outflow)
+                || (x[axis] >= max[axis] as f32 && self.max[axis] == Wall::Outflow)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{prelude::*, math::Vec3A};
+    use crate::{material::MaterialId, params::SimParams, particle::Particle, simulation::Simulation, world::World};
+    use super::{DomainBoundary, Wall};
+
+    #[test]
+    fn walls_apply_their_conditions() {
+        let bounds = (IVec3::ZERO, IVec3::splat(24));
+        let v = Vec3A::new(2., -1., 0.5);
+        let floor = |wall| DomainBoundary::default().with_axis(1, wall);
+        // In the floor band, away from every other wall
+        let pos = IVec3::new(12, 1, 12);
+        assert_eq!(floor(Wall::Sticky).collide(bounds, pos, v), Vec3A::ZERO);
+        assert_eq!(floor(Wall::Slip).collide(bounds, pos, v), Vec3A::new(2., 0., 0.5));
+        assert_eq!(floor(Wall::Periodic).collide(bounds, pos, v), v);
+        assert_eq!(floor(Wall::Outflow).collide(bounds, pos, v), v);
+        let rough = floor(Wall::Separate { friction: 0.5 });
+        assert!(rough.collide(bounds, pos, v).abs_diff_eq(Vec3A::new(2., 0., 0.5) * (1. - 0.5 / 4.25_f32.sqrt()), 1e-5));
+        // Leaving the floor is fine
+        assert_eq!(rough.collide(bounds, pos, -v), -v);
+        // Nodes in the ceiling band
+        assert_eq!(floor(Wall::Slip).collide(bounds, IVec3::new(12, 22, 12), -v), Vec3A::new(-2., 0., -0.5));
+
+        assert!(floor(Wall::Outflow).outflows(bounds, Vec3A::new(12., -0.1, 12.)));
+        assert!(!floor(Wall::Outflow).outflows(bounds, Vec3A::new(-0.1, 12., 12.)));
+    }
+
+    #[test]
+    fn validates_faces() {
+        let (min, max) = (IVec3::ZERO, IVec3::splat(24));
+        assert!(DomainBoundary::default().validate(min, max).is_ok());
+        assert!(DomainBoundary::default().with_axis(0, Wall::Periodic).validate(min, max).is_ok());
+        let mut one_sided = DomainBoundary::default();
+        one_sided.min[0] = Wall::Periodic;
+        assert!(one_sided.validate(min, max).is_err());
+        // Any whole number of chunks wraps, even just one
+        assert!(DomainBoundary::default().with_axis(0, Wall::Periodic).validate(min, IVec3::splat(8)).is_ok());
+        assert!(DomainBoundary::default().with_axis(0, Wall::Periodic).validate(min, IVec3::splat(20)).is_err());
+        assert!(DomainBoundary::default().with_axis(2, Wall::Periodic).validate(IVec3::splat(4), IVec3::splat(28)).is_err());
+        assert!(DomainBoundary::all(Wall::Separate { friction: -1. }).validate(min, max).is_err());
+    }
+
+    // A block of water moving along x with no gravity towards the x faces
+    fn run_along_x(wall: Wall, steps: usize) -> Vec<Particle> {
+        let boundary = DomainBoundary::default().with_axis(0, wall);
+        let mut world = World::with_boundary(IVec3::ZERO, IVec3::splat(24), boundary).unwrap();
+        world.fill_block(Vec3A::new(14.25, 10.25, 10.25), UVec3::splat(8), 0.5, |x| Particle { v: Vec3A::new(1., 0., 0.), ..Particle::new(x, 0.5, MaterialId::water) });
+        let mut sim = Simulation::from_world(world);
+        sim.app.insert_resource(SimParams { gravity: 0., ..Default::default() });
+        sim.run(steps);
+        sim.world().particles()
+    }
+
+    #[test]
+    fn periodic_faces_wrap_around() {
+        let particles = run_along_x(Wall::Periodic, 30);
+        assert_eq!(particles.len(), 512);
+        assert!(particles.iter().all(|p| p.x.x >= 0. && p.x.x < 24.));
+        // Went out through x = 24 and came back in at 0 still going
+        assert!(particles.iter().any(|p| p.x.x < 8.), "nothing wrapped around");
+        let momentum = particles.iter().fold(Vec3A::ZERO, |acc, p| acc + p.m * p.v);
+        assert!((momentum.x - 256.).abs() < 256. * 0.05, "{momentum}");
+    }
+
+    // One chunk wide is its own neighbour on both sides
+    #[test]
+    fn narrow_periodic_domains() {
+        let boundary = DomainBoundary::default().with_axis(0, Wall::Periodic);
+        let mut world = World::with_boundary(IVec3::ZERO, IVec3::new(8, 24, 24), boundary).unwrap();
+        world.fill_block(Vec3A::new(0.5, 10., 8.5), UVec3::new(8, 1, 8), 1., |x| Particle { v: Vec3A::new(1., 0., 0.), ..Particle::new(x, 0.5, MaterialId::water) });
+        let mut sim = Simulation::from_world(world);
+        sim.app.insert_resource(SimParams { gravity: 0., ..Default::default() });
+        sim.run(20);
+        let particles = sim.world().particles();
+        assert_eq!(particles.len(), 64);
+        assert!(particles.iter().all(|p| p.x.x >= 0. && p.x.x < 8.));
+        // A sheet moving along a periodic axis has nothing to stop it
+        let momentum = particles.iter().fold(Vec3A::ZERO, |acc, p| acc + p.m * p.v);
+        assert!((momentum.x - 32.).abs() < 32. * 0.05, "{momentum}");
+    }
+
+    #[test]
+    fn outflow_faces_delete_particles() {
+        let particles = run_along_x(Wall::Outflow, 30);
+        assert!(particles.len() < 512, "nothing flowed out");
+        assert!(particles.iter().all(|p| p.x.x < 24.));
+        // A solid wall keeps them all
+        assert_eq!(run_along_x(Wall::Slip, 30).len(), 512);
+    }
+}
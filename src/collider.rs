@@ -0,0 +1,378 @@
+use bevy::{prelude::*, math::{Vec3A, UVec3}};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// What happens to grid velocity that runs into a collider
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Boundary {
+    /// Moves with the collider, nothing slides
+    Sticky,
+    /// Can't go into it or come off it but slides along it freely
+    Slip,
+    /// Can't go into it but can come off it, sliding is slowed by Coulomb friction
+    Separate { friction: f32 },
+}
+
+impl Boundary {
+    /// Velocity relative to the surface after the condition, n points out of the surface
+    pub fn apply(self, v: Vec3A, n: Vec3A) -> Vec3A {
+        let normal_v = v.dot(n);
+        match self {
+            Boundary::Sticky => Vec3A::ZERO,
+            Boundary::Slip => v - normal_v * n,
+            Boundary::Separate { friction } => {
+                if normal_v >= 0. {
+                    return v;
+                }
+                // The harder it's pushed in the more friction slows the sliding, until it sticks
+                let tangent_v = v - normal_v * n;
+                let speed = tangent_v.length();
+                if speed <= -friction * normal_v {
+                    Vec3A::ZERO
+                }
+                else {
+                    tangent_v * (1. + friction * normal_v / speed)
+                }
+            }
+        }
+    }
+}
+
+/// Shape of a collider in its own frame, centered on the collider's position
+#[derive(Debug, Clone)]
+pub enum Geometry {
+    /// Everything below the plane through the position is solid, normal is unit length
+    Plane { normal: Vec3A },
+    Box { half_extents: Vec3A },
+    Sphere { radius: f32 },
+    /// Around the y axis, half_height is from the center to the centers of the caps
+    Capsule { half_height: f32, radius: f32 },
+    Mesh(MeshSdf),
+}
+
+/// Signed distance to a closed triangle mesh sampled on a grid, negative inside
+#[derive(Debug, Clone)]
+pub struct MeshSdf {
+    origin: Vec3A,
+    spacing: f32,
+    dims: UVec3,
+    values: Vec<f32>,
+}
+
+impl MeshSdf {
+    /// Samples the mesh every spacing, with a couple of samples of room around it
+    pub fn new(triangles: &[[Vec3; 3]], spacing: f32) -> Self {
+        let (min, max) = triangles.iter().flatten().fold(
+            (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+            |(min, max), &v| (min.min(v), max.max(v)),
+        );
+        let origin = min - 2. * spacing;
+        let dims = ((max - min) / spacing).ceil().as_uvec3() + 5;
+        let values = (0..dims.x * dims.y * dims.z).into_par_iter().map(|i| {
+            let index = UVec3::new(i / (dims.y * dims.z), (i / dims.z) % dims.y, i % dims.z);
+            let p = origin + index.as_vec3() * spacing;
+            let distance = triangles.iter()
+                .map(|tri| p.distance(closest_point_on_triangle(p, tri)))
+                .fold(f32::INFINITY, f32::min);
+            if crate::scene::inside_mesh(triangles, p) {-distance} else {distance}
+        }).collect();
+        MeshSdf { origin: origin.into(), spacing, dims, values }
+    }
+
+    /// Box of the mesh itself, without the room around it
+    pub fn bounds(&self) -> (Vec3A, Vec3A) {
+        (self.origin + 2. * self.spacing, self.origin + (self.dims - 3).as_vec3a() * self.spacing)
+    }
+
+    /// Middle of the samples inside the mesh, its center of mass when it's evenly dense
+    pub fn centroid(&self) -> Vec3A {
+        let (count, sum) = self.inside().fold((0, Vec3A::ZERO), |(count, sum), (p, _)| (count + 1, sum + p));
+        sum / count.max(1) as f32
+    }
+
+    /// Moves the mesh by offset
+    pub fn translate(&mut self, offset: Vec3A) {
+        self.origin += offset;
+    }
+
+    /// Every sample inside the mesh and the volume it stands for
+    pub fn inside(&self) -> impl Iterator<Item = (Vec3A, f32)> + '_ {
+        let volume = self.spacing.powi(3);
+        (0..self.values.len() as u32)
+            .filter(|&i| self.values[i as usize] < 0.)
+            .map(move |i| {
+                let index = UVec3::new(i / (self.dims.y * self.dims.z), (i / self.dims.z) % self.dims.y, i % self.dims.z);
+                (self.origin + index.as_vec3a() * self.spacing, volume)
+            })
+    }
+
+    fn value(&self, i: UVec3) -> f32 {
+        self.values[((i.x * self.dims.y + i.y) * self.dims.z + i.z) as usize]
+    }
+
+    /// Trilinear between the samples, outside the sampled box it adds the distance to the box
+    pub fn distance(&self, p: Vec3A) -> f32 {
+        let max = (self.dims - 1).as_vec3a() * self.spacing;
+        let local = (p - self.origin).clamp(Vec3A::ZERO, max);
+        let outside = (p - self.origin).distance(local);
+
+        let cell = (local / self.spacing).floor().min((self.dims - 2).as_vec3a());
+        let t = local / self.spacing - cell;
+        let i = cell.as_uvec3();
+        let mut distance = 0.;
+        for corner in 0..8 {
+            let offset = UVec3::new(corner >> 2, (corner >> 1) & 1, corner & 1);
+            let o = offset.as_vec3a();
+            let w = o * t + (1. - o) * (1. - t);
+            distance += w.x * w.y * w.z * self.value(i + offset);
+        }
+        distance + outside
+    }
+}
+
+// Ericson's Real-Time Collision Detection, checks which feature of the triangle is closest
+fn closest_point_on_triangle(p: Vec3, [a, b, c]: &[Vec3; 3]) -> Vec3 {
+    let (ab, ac, ap) = (*b - *a, *c - *a, p - *a);
+    let (d1, d2) = (ab.dot(ap), ac.dot(ap));
+    if d1 <= 0. && d2 <= 0. {
+        return *a;
+    }
+    let bp = p - *b;
+    let (d3, d4) = (ab.dot(bp), ac.dot(bp));
+    if d3 >= 0. && d4 <= d3 {
+        return *b;
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0. && d1 >= 0. && d3 <= 0. {
+        return *a + ab * (d1 / (d1 - d3));
+    }
+    let cp = p - *c;
+    let (d5, d6) = (ab.dot(cp), ac.dot(cp));
+    if d6 >= 0. && d5 <= d6 {
+        return *c;
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0. && d2 >= 0. && d6 <= 0. {
+        return *a + ac * (d2 / (d2 - d6));
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0. && d4 - d3 >= 0. && d5 - d6 >= 0. {
+        return *b + (*c - *b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+    let denom = 1. / (va + vb + vc);
+    *a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+// Triangles of a cube from min that's size wide, facing out
+#[cfg(test)]
+pub(crate) fn box_triangles(min: Vec3, size: f32) -> Vec<[Vec3; 3]> {
+    let v = [[0., 0., 0.], [1., 0., 0.], [1., 1., 0.], [0., 1., 0.], [0., 0., 1.], [1., 0., 1.], [1., 1., 1.], [0., 1., 1.]]
+        .map(|v| min + Vec3::from_array(v) * size);
+    let quads = [[0, 3, 2, 1], [4, 5, 6, 7], [0, 1, 5, 4], [3, 7, 6, 2], [0, 4, 7, 3], [1, 2, 6, 5]];
+    quads.iter()
+        .flat_map(|q| [[v[q[0]], v[q[1]], v[q[2]]], [v[q[0]], v[q[2]], v[q[3]]]])
+        .collect()
+}
+
+impl Geometry {
+    pub fn distance(&self, p: Vec3A) -> f32 {
+        match self {
+            Geometry::Plane { normal } => p.dot(*normal),
+            Geometry::Box { half_extents } => {
+                let q = p.abs() - *half_extents;
+                q.max(Vec3A::ZERO).length() + q.max_element().min(0.)
+            }
+            Geometry::Sphere { radius } => p.length() - radius,
+            Geometry::Capsule { half_height, radius } => {
+                let axis = Vec3A::new(0., p.y.clamp(-half_height, *half_height), 0.);
+                p.distance(axis) - radius
+            }
+            Geometry::Mesh(sdf) => sdf.distance(p),
+        }
+    }
+
+    /// Point of the shape furthest along dir, in its own frame. Planes go on forever so they
+    /// give the origin, meshes give a corner of their box
+    pub fn support(&self, dir: Vec3A) -> Vec3A {
+        let sign = Vec3A::select(dir.cmpge(Vec3A::ZERO), Vec3A::ONE, Vec3A::NEG_ONE);
+        match self {
+            Geometry::Plane { .. } => Vec3A::ZERO,
+            Geometry::Box { half_extents } => sign * *half_extents,
+            Geometry::Sphere { radius } => dir.normalize_or_zero() * *radius,
+            Geometry::Capsule { half_height, radius } => Vec3A::new(0., sign.y * half_height, 0.) + dir.normalize_or_zero() * *radius,
+            Geometry::Mesh(sdf) => {
+                let (min, max) = sdf.bounds();
+                Vec3A::select(dir.cmpge(Vec3A::ZERO), max, min)
+            }
+        }
+    }
+}
+
+/// A solid the material can't go through, kinematic ones move with their velocities every step
+#[derive(Debug, Clone)]
+pub struct Collider {
+    pub geometry: Geometry,
+    pub position: Vec3A,
+    pub rotation: Quat,
+    pub velocity: Vec3A,
+    /// Axis times radians per unit of time, around the position
+    pub angular_velocity: Vec3A,
+    pub boundary: Boundary,
+}
+
+impl Collider {
+    /// A static collider, set the velocities for a kinematic one
+    pub fn new(geometry: Geometry, position: Vec3A, boundary: Boundary) -> Self {
+        Collider {
+            geometry,
+            position,
+            rotation: Quat::IDENTITY,
+            velocity: Vec3A::ZERO,
+            angular_velocity: Vec3A::ZERO,
+            boundary,
+        }
+    }
+
+    /// Signed distance from a point in the world, negative inside
+    pub fn distance(&self, x: Vec3A) -> f32 {
+        self.geometry.distance(self.rotation.inverse() * (x - self.position))
+    }
+
+    /// Direction out of the collider, the gradient of the distance
+    pub fn normal(&self, x: Vec3A) -> Vec3A {
+        let h = 1e-2;
+        let diff = |axis: Vec3A| self.distance(x + h * axis) - self.distance(x - h * axis);
+        Vec3A::new(diff(Vec3A::X), diff(Vec3A::Y), diff(Vec3A::Z)).normalize_or_zero()
+    }
+
+    /// Velocity of the collider's surface at x
+    pub fn velocity_at(&self, x: Vec3A) -> Vec3A {
+        self.velocity + self.angular_velocity.cross(x - self.position)
+    }
+
+    /// Velocity v at x after the boundary condition, unchanged outside the collider
+    pub fn collide(&self, x: Vec3A, v: Vec3A) -> Vec3A {
+        if self.distance(x) > 0. {
+            return v;
+        }
+        let surface_v = self.velocity_at(x);
+        surface_v + self.boundary.apply(v - surface_v, self.normal(x))
+    }
+
+    /// Moves a kinematic collider along by dt
+    pub fn advance(&mut self, dt: f32) {
+        self.position += self.velocity * dt;
+        self.rotation = (Quat::from_scaled_axis((self.angular_velocity * dt).into()) * self.rotation).normalize();
+    }
+}
+
+/// Every collider in the simulation, update_grid applies their boundary conditions to the nodes
+/// inside them
+#[derive(Resource, Debug, Clone, Default)]
+pub struct Colliders(pub Vec<Collider>);
+
+impl Colliders {
+    /// Runs the velocity of a node at x through every collider it's in
+    pub fn collide(&self, x: Vec3A, v: Vec3A) -> Vec3A {
+        self.0.iter().fold(v, |v, c| c.collide(x, v))
+    }
+
+    /// How far to move a particle at x so it's out of every collider it ended up in
+    pub fn push_out(&self, x: Vec3A) -> Vec3A {
+        self.0.iter().fold(Vec3A::ZERO, |offset, c| {
+            let distance = c.distance(x + offset);
+            if distance >= 0. {
+                return offset;
+            }
+            offset - distance * c.normal(x + offset)
+        })
+    }
+
+    pub fn advance(&mut self, dt: f32) {
+        for collider in self.0.iter_mut() {
+            collider.advance(dt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{prelude::*, math::Vec3A};
+    use crate::{material::MaterialId, particle::Particle, simulation::Simulation, world::World};
+    use super::{box_triangles, Boundary, Collider, Colliders, Geometry, MeshSdf};
+
+    #[test]
+    fn shapes_have_signed_distances() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        let sphere = Collider::new(Geometry::Sphere { radius: 2. }, Vec3A::splat(5.), Boundary::Sticky);
+        assert!(close(sphere.distance(Vec3A::new(5., 9., 5.)), 2.));
+        assert!(close(sphere.distance(Vec3A::splat(5.)), -2.));
+
+        let plane = Collider::new(Geometry::Plane { normal: Vec3A::Y }, Vec3A::new(0., 3., 0.), Boundary::Sticky);
+        assert!(close(plane.distance(Vec3A::new(7., 1., -4.)), -2.));
+        assert!(plane.normal(Vec3A::new(7., 1., -4.)).abs_diff_eq(Vec3A::Y, 1e-4));
+
+        // Turned a quarter around z the long side points up
+        let mut cuboid = Collider::new(Geometry::Box { half_extents: Vec3A::new(4., 1., 1.) }, Vec3A::ZERO, Boundary::Sticky);
+        cuboid.rotation = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+        assert!(close(cuboid.distance(Vec3A::new(0., 5., 0.)), 1.));
+        assert!(close(cuboid.distance(Vec3A::new(3., 0., 0.)), 2.));
+        assert!(close(cuboid.distance(Vec3A::ZERO), -1.));
+
+        let capsule = Collider::new(Geometry::Capsule { half_height: 3., radius: 1. }, Vec3A::ZERO, Boundary::Sticky);
+        assert!(close(capsule.distance(Vec3A::new(0., 6., 0.)), 2.));
+        assert!(close(capsule.distance(Vec3A::new(2., 2., 0.)), 1.));
+    }
+
+    #[test]
+    fn boundary_conditions() {
+        let floor = |boundary| Collider::new(Geometry::Plane { normal: Vec3A::Y }, Vec3A::ZERO, boundary);
+        let inside = Vec3A::new(0., -0.5, 0.);
+        let v = Vec3A::new(3., -1., 0.);
+        assert_eq!(floor(Boundary::Sticky).collide(inside, v), Vec3A::ZERO);
+        assert!(floor(Boundary::Slip).collide(inside, v).abs_diff_eq(Vec3A::new(3., 0., 0.), 1e-4));
+        // Friction takes 0.5 * 1 off the sliding speed
+        let friction = floor(Boundary::Separate { friction: 0.5 });
+        assert!(friction.collide(inside, v).abs_diff_eq(Vec3A::new(2.5, 0., 0.), 1e-4));
+        // Sticks once friction is stronger than the sliding
+        assert!(friction.collide(inside, Vec3A::new(0.4, -1., 0.)).abs_diff_eq(Vec3A::ZERO, 1e-4));
+        // Coming off the floor and anywhere outside it is left alone
+        assert_eq!(friction.collide(inside, Vec3A::new(3., 1., 0.)), Vec3A::new(3., 1., 0.));
+        assert_eq!(friction.collide(-inside, v), v);
+
+        // Moving colliders drag things along relative to their own velocity
+        let mut belt = floor(Boundary::Sticky);
+        belt.velocity = Vec3A::new(0., 0., 2.);
+        assert_eq!(belt.collide(inside, v), belt.velocity);
+        belt.advance(0.5);
+        assert_eq!(belt.position, Vec3A::new(0., 0., 1.));
+    }
+
+    #[test]
+    fn mesh_sdf_matches_box() {
+        let triangles = box_triangles(Vec3::ZERO, 4.);
+        let sdf = Geometry::Mesh(MeshSdf::new(&triangles, 0.5));
+        let cuboid = Geometry::Box { half_extents: Vec3A::splat(2.) };
+        for p in [Vec3A::splat(2.), Vec3A::new(2., 5., 2.), Vec3A::new(-1., 0.5, 3.), Vec3A::new(3.5, 3.5, 1.), Vec3A::new(12., 2., 2.)] {
+            let expected = cuboid.distance(p - 2.);
+            assert!((sdf.distance(p) - expected).abs() < 0.3, "{p}: {} instead of {expected}", sdf.distance(p));
+        }
+    }
+
+    // Water dropped onto a shelf in the middle of the domain spreads out on top of it
+    #[test]
+    fn water_lands_on_a_shelf() {
+        let mut world = World::with_bounds(IVec3::ZERO, IVec3::splat(24));
+        world.fill_block(Vec3A::new(10.25, 14.25, 10.25), UVec3::splat(8), 0.5, |x| Particle::new(x, 0.5, MaterialId::water));
+        let mut sim = Simulation::from_world(world);
+        let shelf = Collider::new(Geometry::Box { half_extents: Vec3A::new(8., 1., 8.) }, Vec3A::new(12., 9., 12.), Boundary::Separate { friction: 0.5 });
+        sim.app.insert_resource(Colliders(vec![shelf]));
+        sim.run(30);
+
+        let particles = sim.world().particles();
+        let lowest = particles.iter().map(|p| p.x.y).fold(f32::MAX, f32::min);
+        assert!(lowest > 9.9, "fell through the shelf down to {lowest}");
+        let spread = particles.iter().map(|p| (p.x.x - 12.).abs()).fold(0., f32::max);
+        assert!(spread > 3., "didn't spread out, only {spread} from the middle");
+    }
+}
@@ -0,0 +1,104 @@
+#![allow(non_upper_case_globals, non_snake_case, dead_code)]
+use bevy::{prelude::*, ecs::schedule::ScheduleLabel};
+
+pub mod boundary;
+pub mod collider;
+pub mod material;
+pub mod morton;
+pub mod params;
+pub mod particle;
+pub mod rigid;
+pub mod scene;
+pub mod simulation;
+pub mod solver;
+pub mod svd;
+pub mod timestep;
+pub mod world;
+
+pub use crate::boundary::{DomainBoundary, Wall};
+pub use crate::collider::{Boundary, Collider, Colliders, Geometry, MeshSdf};
+pub use crate::material::{ConstitutiveModel, Fluid, MaterialId, Materials, NeoHookean, NonNewtonian, Sand, Snow};
+pub use crate::params::SimParams;
+pub use crate::particle::Particle;
+pub use crate::rigid::{MpmRigidBody, RigidBodies};
+pub use crate::scene::{MpmScene, SceneFile};
+pub use crate::simulation::Simulation;
+pub use crate::timestep::TimeStep;
+pub use crate::world::{Chunk, Neighborhood, Node, World};
+
+/// Schedule holding one solver substep, `MpmPlugin` runs `World::substeps` of them for every
+/// step so that chunks can step at their own rate, and as many steps as it takes to cover
+/// `SimParams::frame_time`
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MpmSchedule;
+
+/// The solver stages, in the order they run inside `MpmSchedule`
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MpmSet {
+    Schedule,
+    ClearGrid,
+    P2G1,
+    P2G2,
+    UpdateGrid,
+    G2P,
+    Redistribute,
+}
+
+/// The set the `MpmSchedule` runner lives in, order your own `Update` systems against this
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MpmStep;
+
+/// Registers the `World`, `SimParams`, `Materials` and `Colliders` resources and the solver systems, and loads the scene
+/// in `SceneFile` if there is one
+pub struct MpmPlugin;
+
+impl Plugin for MpmPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<World>()
+            .init_resource::<SimParams>()
+            .init_resource::<Materials>()
+            .init_resource::<Colliders>()
+            .init_resource::<RigidBodies>()
+            .init_resource::<TimeStep>()
+            .configure_sets(MpmSchedule, (
+                    MpmSet::Schedule,
+                    MpmSet::ClearGrid,
+                    MpmSet::P2G1,
+                    MpmSet::P2G2,
+                    MpmSet::UpdateGrid,
+                    MpmSet::G2P,
+                    MpmSet::Redistribute,
+                    ).chain())
+            .add_systems(MpmSchedule, (
+                    solver::schedule_substep.in_set(MpmSet::Schedule),
+                    solver::clear_grid.in_set(MpmSet::ClearGrid),
+                    solver::p2g1.in_set(MpmSet::P2G1),
+                    solver::p2g2.in_set(MpmSet::P2G2),
+                    solver::update_grid.in_set(MpmSet::UpdateGrid),
+                    solver::g2p.in_set(MpmSet::G2P),
+                    solver::redistribute.in_set(MpmSet::Redistribute),
+                    ))
+            .add_systems(PreStartup, scene::load_scene)
+            .add_systems(Update, (params::check_params, run_mpm_schedule.in_set(MpmStep)).chain());
+    }
+}
+
+fn run_mpm_schedule(world: &mut bevy::ecs::world::World) {
+    let params = world.resource::<SimParams>().clone();
+    let mut remaining = params.frame_time;
+    while remaining > TimeStep::epsilon {
+        let wave_speeds = world.resource::<Materials>().wave_speeds(&params);
+        let max_dts = world.resource::<Materials>().max_dts(&params);
+        rigid::gather_bodies(world);
+        let speed = world.resource::<World>().max_signal_speed(&wave_speeds)
+            .max(world.resource::<RigidBodies>().max_speed());
+        let stable_dt = world.resource::<World>().stable_dt(&max_dts);
+        let dt = world.resource_mut::<TimeStep>().pick(&params, speed, stable_dt, remaining);
+        for _ in 0..World::substeps {
+            world.run_schedule(MpmSchedule);
+        }
+        rigid::step_bodies(world, dt);
+        world.resource_mut::<Colliders>().advance(dt);
+        remaining -= dt;
+    }
+}
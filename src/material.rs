@@ -0,0 +1,870 @@
+use bevy::{prelude::*, math::{Vec3A, Mat3A}};
+use crate::{params::SimParams, particle::Particle, svd::svd};
+
+/// Which model in `Materials` a particle is made of
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MaterialId(pub u16);
+
+impl MaterialId {
+    /// `Materials` always starts with water so this is what particles are by default
+    pub const water: MaterialId = MaterialId(0);
+}
+
+/// Turns the state of a particle into the stress p2g2 spreads onto the grid as force
+/// Implement this and add it to `Materials` to get a new material, the transfers don't change
+pub trait ConstitutiveModel: Send + Sync + 'static {
+    /// Cauchy stress of the particle, density is the grid mass around it from p2g1
+    fn stress(&self, p: &Particle, density: f32, params: &SimParams) -> Mat3A;
+
+    /// Volume the stress acts over, fluids fill whatever room their mass takes up at the
+    /// density they're at
+    fn volume(&self, p: &Particle, density: f32) -> f32 {
+        p.m / density
+    }
+
+    /// Fastest a wave can go through the material at rest, dt gets picked so it doesn't skip
+    /// over nodes
+    fn wave_speed(&self, params: &SimParams) -> f32;
+
+    /// Longest substep the model stays stable for on top of the CFL limit, things that diffuse
+    /// like viscosity blow up explicit steps that are too long no matter how slow it all moves
+    fn max_dt(&self, _params: &SimParams) -> f32 {
+        f32::INFINITY
+    }
+
+    /// Runs in g2p after F is updated, plastic materials move whatever deformation is past what
+    /// they can hold out of F here
+    fn project(&self, _p: &mut Particle) {}
+}
+
+/// Weakly compressible Newtonian fluid, Tait pressure plus viscosity from the deviatoric strain
+/// rate, the constants are in `SimParams`
+#[derive(Debug, Clone, Copy)]
+pub struct Fluid;
+
+// Tait, a little tension is allowed but not enough for the fluid to clump up
+fn tait_pressure(density: f32, params: &SimParams) -> f32 {
+    (params.eos_stiffness * ((density / params.rest_density).powf(params.eos_power) - 1.)).max(-0.1)
+}
+
+// Viscosity spreads momentum over dx^2 rest_density / (6 viscosity), a step longer than that
+// overshoots. dx is a node
+fn viscous_dt(viscosity: f32, params: &SimParams) -> f32 {
+    params.rest_density / (6. * viscosity)
+}
+
+// C is the velocity gradient, its symmetric part is the strain rate and rotation doesn't count.
+// The change in volume is handled by the pressure so only the deviatoric part is viscous
+fn deviatoric_strain_rate(C: Mat3A) -> Mat3A {
+    let strain_rate = 0.5 * (C + C.transpose());
+    let trace = strain_rate.x_axis.x + strain_rate.y_axis.y + strain_rate.z_axis.z;
+    strain_rate - Mat3A::from_diagonal((trace / 3.) * Vec3::ONE)
+}
+
+impl ConstitutiveModel for Fluid {
+    fn stress(&self, p: &Particle, density: f32, params: &SimParams) -> Mat3A {
+        let pressure = tait_pressure(density, params);
+        let deviatoric = deviatoric_strain_rate(p.C);
+        Mat3A::from_diagonal(-pressure * Vec3::ONE) + 2. * params.dynamic_viscosity * deviatoric
+    }
+
+    fn wave_speed(&self, params: &SimParams) -> f32 {
+        params.wave_speed()
+    }
+
+    fn max_dt(&self, params: &SimParams) -> f32 {
+        viscous_dt(params.dynamic_viscosity, params)
+    }
+}
+
+/// Fluid whose viscosity depends on how fast it's sheared, Herschel-Bulkley. Once it's flowing the
+/// shear stress is yield_stress + consistency * rate^flow_index, below the yield stress it barely
+/// creeps. Pressure comes from the same EOS as `Fluid`
+#[derive(Debug, Clone, Copy)]
+pub struct NonNewtonian {
+    pub yield_stress: f32,
+    /// K, the viscosity at a shear rate of 1
+    pub consistency: f32,
+    /// n, below 1 it gets runnier the faster it's sheared like paint, above 1 it thickens
+    pub flow_index: f32,
+    /// Cap on the viscosity so unyielded and barely moving fluid doesn't blow up, the higher it
+    /// is the less it creeps and the smaller the steps get
+    pub max_viscosity: f32,
+}
+
+impl NonNewtonian {
+    /// Doesn't move until it's pushed hard enough, then flows with a constant viscosity. Mud, lava
+    pub fn bingham(yield_stress: f32, viscosity: f32) -> Self {
+        NonNewtonian::herschel_bulkley(yield_stress, viscosity, 1.)
+    }
+
+    /// No yield stress, only a viscosity that changes with shear rate. Paint, blood
+    pub fn power_law(consistency: f32, flow_index: f32) -> Self {
+        NonNewtonian::herschel_bulkley(0., consistency, flow_index)
+    }
+
+    /// Yield stress and shear thinning. Toothpaste, ketchup
+    pub fn herschel_bulkley(yield_stress: f32, consistency: f32, flow_index: f32) -> Self {
+        NonNewtonian { yield_stress, consistency, flow_index, max_viscosity: 10. }
+    }
+
+    /// Effective viscosity at a shear rate
+    pub fn viscosity(&self, shear_rate: f32) -> f32 {
+        if shear_rate <= 0. {
+            return self.max_viscosity;
+        }
+        let viscosity = self.yield_stress / shear_rate + self.consistency * shear_rate.powf(self.flow_index - 1.);
+        viscosity.min(self.max_viscosity)
+    }
+}
+
+impl ConstitutiveModel for NonNewtonian {
+    fn stress(&self, p: &Particle, density: f32, params: &SimParams) -> Mat3A {
+        let pressure = tait_pressure(density, params);
+        let deviatoric = deviatoric_strain_rate(p.C);
+        // sqrt(2 D:D), which is just the shear rate for simple shear
+        let norm_squared = deviatoric.x_axis.length_squared() + deviatoric.y_axis.length_squared() + deviatoric.z_axis.length_squared();
+        let shear_rate = (2. * norm_squared).sqrt();
+        Mat3A::from_diagonal(-pressure * Vec3::ONE) + 2. * self.viscosity(shear_rate) * deviatoric
+    }
+
+    fn wave_speed(&self, params: &SimParams) -> f32 {
+        params.wave_speed()
+    }
+
+    // Unyielded it's as thick as it gets
+    fn max_dt(&self, params: &SimParams) -> f32 {
+        viscous_dt(self.max_viscosity, params)
+    }
+}
+
+/// Elastic solid that always springs back to the shape it started in, jelly and rubber
+#[derive(Debug, Clone, Copy)]
+pub struct NeoHookean {
+    pub youngs_modulus: f32,
+    pub poisson_ratio: f32,
+    /// Mass per volume at rest, only used for the wave speed
+    pub density: f32,
+}
+
+/// Lamé parameters (mu, lambda) from Young's modulus and Poisson's ratio, mu is the shear modulus
+pub fn lame(youngs_modulus: f32, poisson_ratio: f32) -> (f32, f32) {
+    let (e, nu) = (youngs_modulus, poisson_ratio);
+    let mu = e / (2. * (1. + nu));
+    let lambda = e * nu / ((1. + nu) * (1. - 2. * nu));
+    (mu, lambda)
+}
+
+impl NeoHookean {
+    pub fn lame(&self) -> (f32, f32) {
+        lame(self.youngs_modulus, self.poisson_ratio)
+    }
+}
+
+impl ConstitutiveModel for NeoHookean {
+    fn stress(&self, p: &Particle, _: f32, _: &SimParams) -> Mat3A {
+        let (mu, lambda) = self.lame();
+        let F = p.F;
+        let J = F.determinant();
+        // Kirchhoff stress mu (F F^T - I) + lambda ln(J) I, over J for Cauchy
+        let kirchhoff = mu * (F * F.transpose() - Mat3A::IDENTITY) + Mat3A::from_diagonal(lambda * J.ln() * Vec3::ONE);
+        kirchhoff * (1. / J)
+    }
+
+    // The volume the particle started with stretched by however much F stretched it
+    fn volume(&self, p: &Particle, _: f32) -> f32 {
+        p.V0 * p.F.determinant()
+    }
+
+    // P waves are the fast ones
+    fn wave_speed(&self, _: &SimParams) -> f32 {
+        let (mu, lambda) = self.lame();
+        ((lambda + 2. * mu) / self.density).sqrt()
+    }
+}
+
+/// Stomakhin et al. 2013 snow, elastic until it's squashed or stretched too far, then it gives way
+/// and gets harder the more it's packed down
+#[derive(Debug, Clone, Copy)]
+pub struct Snow {
+    pub youngs_modulus: f32,
+    pub poisson_ratio: f32,
+    /// How far it can be squashed before breaking, theta_c in the paper
+    pub critical_compression: f32,
+    /// How far it can be stretched before breaking, theta_s
+    pub critical_stretch: f32,
+    /// How much harder packing makes it, xi
+    pub hardening: f32,
+    /// Mass per volume at rest, only used for the wave speed
+    pub density: f32,
+}
+
+impl Snow {
+    /// Hardening can't scale the Lamé parameters past these, without it stretched snow turns to
+    /// dust and packed snow turns to rock
+    pub const hardening_range: (f32, f32) = (0.1, 5.);
+
+    /// Lamé parameters before hardening
+    pub fn lame(&self) -> (f32, f32) {
+        lame(self.youngs_modulus, self.poisson_ratio)
+    }
+}
+
+impl Default for Snow {
+    // The paper's reference snow, with the stiffness brought down to grid units
+    fn default() -> Self {
+        Snow {
+            youngs_modulus: 30.,
+            poisson_ratio: 0.2,
+            critical_compression: 2.5e-2,
+            critical_stretch: 7.5e-3,
+            hardening: 10.,
+            density: 4.,
+        }
+    }
+}
+
+impl ConstitutiveModel for Snow {
+    // Fixed corotated with the Lamé parameters scaled by exp(xi (1 - Jp))
+    fn stress(&self, p: &Particle, _: f32, _: &SimParams) -> Mat3A {
+        let (mu, lambda) = self.lame();
+        let (min_h, max_h) = Snow::hardening_range;
+        let h = (self.hardening * (1. - p.Jp)).exp().clamp(min_h, max_h);
+        let (mu, lambda) = (mu * h, lambda * h);
+        let (u, _, v) = svd(p.F);
+        let R = u * v.transpose();
+        let J = p.F.determinant();
+        let kirchhoff = 2. * mu * (p.F - R) * p.F.transpose() + Mat3A::from_diagonal(lambda * (J - 1.) * J * Vec3::ONE);
+        kirchhoff * (1. / J)
+    }
+
+    fn volume(&self, p: &Particle, _: f32) -> f32 {
+        p.V0 * p.F.determinant()
+    }
+
+    // Packed as hard as it gets
+    fn wave_speed(&self, _: &SimParams) -> f32 {
+        let (mu, lambda) = self.lame();
+        ((lambda + 2. * mu) * Snow::hardening_range.1 / self.density).sqrt()
+    }
+
+    // Clamps the stretches to what snow can take, what's left over goes into Jp
+    fn project(&self, p: &mut Particle) {
+        let (u, sigma, v) = svd(p.F);
+        let clamped = sigma.clamp(
+            Vec3A::splat(1. - self.critical_compression),
+            Vec3A::splat(1. + self.critical_stretch),
+        );
+        p.Jp *= (sigma.x * sigma.y * sigma.z) / (clamped.x * clamped.y * clamped.z);
+        p.F = u * Mat3A::from_diagonal(clamped.into()) * v.transpose();
+    }
+}
+
+/// Drucker-Prager sand from Klár et al. 2016, elastic in Hencky strain and returned to the yield
+/// cone in g2p. Piles up at its friction angle, falls apart when pulled
+#[derive(Debug, Clone, Copy)]
+pub struct Sand {
+    pub youngs_modulus: f32,
+    pub poisson_ratio: f32,
+    /// Steepest slope in degrees a pile can hold
+    pub friction_angle: f32,
+    /// How far it can be pulled apart in log strain before it falls apart, 0 for dry sand
+    pub cohesion: f32,
+    /// Mass per volume at rest, only used for the wave speed
+    pub density: f32,
+}
+
+impl Default for Sand {
+    // Stiff enough that a pile isn't squashed by its own weight, it's the friction that matters
+    fn default() -> Self {
+        Sand {
+            youngs_modulus: 300.,
+            poisson_ratio: 0.3,
+            friction_angle: 30.,
+            cohesion: 0.,
+            density: 4.,
+        }
+    }
+}
+
+impl Sand {
+    pub fn lame(&self) -> (f32, f32) {
+        lame(self.youngs_modulus, self.poisson_ratio)
+    }
+
+    /// Slope of the yield cone
+    pub fn alpha(&self) -> f32 {
+        let sin_phi = self.friction_angle.to_radians().sin();
+        (2_f32 / 3.).sqrt() * 2. * sin_phi / (3. - sin_phi)
+    }
+
+    /// Log of the principal stretches, inverted particles are treated as squashed almost flat
+    fn hencky(sigma: Vec3A) -> Vec3A {
+        Vec3A::from_array(sigma.to_array().map(|s| s.max(1e-4).ln()))
+    }
+}
+
+impl ConstitutiveModel for Sand {
+    // Kirchhoff stress 2 mu eps + lambda tr(eps) I in the principal directions, over J for Cauchy
+    fn stress(&self, p: &Particle, _: f32, _: &SimParams) -> Mat3A {
+        let (mu, lambda) = self.lame();
+        let (u, sigma, _) = svd(p.F);
+        let eps = Sand::hencky(sigma);
+        let principal = 2. * mu * eps + Vec3A::splat(lambda * eps.dot(Vec3A::ONE));
+        let kirchhoff = u * Mat3A::from_diagonal(principal.into()) * u.transpose();
+        kirchhoff * (1. / p.F.determinant())
+    }
+
+    fn volume(&self, p: &Particle, _: f32) -> f32 {
+        p.V0 * p.F.determinant()
+    }
+
+    fn wave_speed(&self, _: &SimParams) -> f32 {
+        let (mu, lambda) = self.lame();
+        ((lambda + 2. * mu) / self.density).sqrt()
+    }
+
+    fn project(&self, p: &mut Particle) {
+        let (mu, lambda) = self.lame();
+        let (u, sigma, v) = svd(p.F);
+        let eps = Sand::hencky(sigma);
+        // Volume correction, sand that's been pulled apart has to be pushed back together before
+        // it pushes back. Cohesion moves the tip of the cone out into tension
+        let shift = p.Jp.ln() / 3. - self.cohesion;
+        let shifted = eps + Vec3A::splat(shift);
+        let trace = shifted.dot(Vec3A::ONE);
+        let deviatoric = shifted - Vec3A::splat(trace / 3.);
+        let dev_norm = deviatoric.length();
+        let yield_value = dev_norm + (3. * lambda + 2. * mu) / (2. * mu) * trace * self.alpha();
+
+        let projected = if trace >= 0. {
+            // Pulled apart, nothing holds it together past the cohesion so it goes to the tip
+            Vec3A::splat(self.cohesion)
+        }
+        else if yield_value <= 0. {
+            // Inside the cone, all elastic
+            return;
+        }
+        else {
+            // Slides along the cone back to the edge of it
+            shifted - yield_value / dev_norm * deviatoric + Vec3A::splat(self.cohesion)
+        };
+        let new_sigma = Vec3A::from_array(projected.to_array().map(f32::exp));
+        p.Jp *= (sigma.x * sigma.y * sigma.z) / (new_sigma.x * new_sigma.y * new_sigma.z);
+        p.F = u * Mat3A::from_diagonal(new_sigma.into()) * v.transpose();
+    }
+}
+
+/// Every material particles can be made of, a `MaterialId` is an index into this
+#[derive(Resource)]
+pub struct Materials {
+    materials: Vec<Material>,
+}
+
+struct Material {
+    name: String,
+    model: Box<dyn ConstitutiveModel>,
+    color: Color,
+}
+
+impl Default for Materials {
+    fn default() -> Self {
+        let mut materials = Materials { materials: vec![] };
+        materials.insert("water", Box::new(Fluid), Color::rgb(0.1, 0.4, 0.9));
+        materials.insert("jelly", Box::new(NeoHookean { youngs_modulus: 30., poisson_ratio: 0.3, density: 4. }), Color::rgb(0.9, 0.2, 0.5));
+        materials.insert("snow", Box::new(Snow::default()), Color::rgb(0.75, 0.8, 0.85));
+        materials.insert("sand", Box::new(Sand::default()), Color::rgb(0.85, 0.7, 0.4));
+        materials.insert("mud", Box::new(NonNewtonian::bingham(0.5, 1.)), Color::rgb(0.4, 0.25, 0.1));
+        materials.insert("lava", Box::new(NonNewtonian::bingham(0.2, 5.)), Color::rgb(1., 0.35, 0.));
+        materials.insert("paint", Box::new(NonNewtonian::power_law(1., 0.5)), Color::rgb(0.2, 0.7, 0.3));
+        materials.insert("toothpaste", Box::new(NonNewtonian::herschel_bulkley(2., 1., 0.5)), Color::rgb(0.3, 0.8, 0.8));
+        materials
+    }
+}
+
+impl Materials {
+    /// Colours handed to materials added without one, cycled through by id
+    const palette: [Color; 6] = [Color::PURPLE, Color::OLIVE, Color::TEAL, Color::MAROON, Color::NAVY, Color::GRAY];
+
+    /// Registers a model under a name, adding one with a name that's taken replaces the old
+    /// model and keeps its id so existing particles switch over
+    pub fn add(&mut self, name: &str, model: impl ConstitutiveModel) -> MaterialId {
+        let color = Self::palette[self.materials.len() % Self::palette.len()];
+        self.insert(name, Box::new(model), color)
+    }
+
+    // Replacing keeps the old colour too, only the model changes
+    fn insert(&mut self, name: &str, model: Box<dyn ConstitutiveModel>, color: Color) -> MaterialId {
+        if let Some(id) = self.id(name) {
+            self.materials[id.0 as usize].model = model;
+            return id;
+        }
+        self.materials.push(Material { name: name.to_string(), model, color });
+        MaterialId(self.materials.len() as u16 - 1)
+    }
+
+    pub fn id(&self, name: &str) -> Option<MaterialId> {
+        self.materials.iter().position(|m| m.name == name).map(|i| MaterialId(i as u16))
+    }
+
+    pub fn name(&self, id: MaterialId) -> &str {
+        &self.materials[id.0 as usize].name
+    }
+
+    /// Panics on ids that didn't come from this registry
+    pub fn model(&self, id: MaterialId) -> &dyn ConstitutiveModel {
+        &*self.materials[id.0 as usize].model
+    }
+
+    /// What particles of this material get drawn as
+    pub fn color(&self, id: MaterialId) -> Color {
+        self.materials[id.0 as usize].color
+    }
+
+    pub fn set_color(&mut self, id: MaterialId, color: Color) {
+        self.materials[id.0 as usize].color = color;
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.materials.iter().map(|m| m.name.as_str())
+    }
+
+    /// Wave speed of every material indexed by `MaterialId`, for `World::max_signal_speed`
+    pub fn wave_speeds(&self, params: &SimParams) -> Vec<f32> {
+        self.materials.iter().map(|m| m.model.wave_speed(params)).collect()
+    }
+
+    /// `ConstitutiveModel::max_dt` of every material indexed by `MaterialId`, for
+    /// `World::stable_dt`
+    pub fn max_dts(&self, params: &SimParams) -> Vec<f32> {
+        self.materials.iter().map(|m| m.model.max_dt(params)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{prelude::*, math::{Vec3A, Mat3A}, utils::HashMap};
+    use crate::{boundary::{DomainBoundary, Wall}, params::SimParams, particle::Particle, simulation::Simulation, solver::stencil_weights, world::{Chunk, World}};
+    use super::{ConstitutiveModel, Fluid, MaterialId, Materials, NeoHookean, NonNewtonian, Sand, Snow};
+
+    // Never pushes back no matter what
+    struct Dust;
+
+    impl ConstitutiveModel for Dust {
+        fn stress(&self, _: &Particle, _: f32, _: &SimParams) -> Mat3A {
+            Mat3A::ZERO
+        }
+
+        fn wave_speed(&self, _: &SimParams) -> f32 {
+            0.
+        }
+    }
+
+    #[test]
+    fn looks_up_by_name() {
+        let mut materials = Materials::default();
+        assert_eq!(materials.id("water"), Some(MaterialId::water));
+        let dust = materials.add("dust", Dust);
+        assert_eq!(dust, MaterialId(8));
+        assert_eq!(materials.name(dust), "dust");
+        assert_eq!(materials.add("dust", Dust), dust);
+        assert_eq!(materials.names().collect::<Vec<_>>(), ["water", "jelly", "snow", "sand", "mud", "lava", "paint", "toothpaste", "dust"]);
+        assert_eq!(materials.id("plasma"), None);
+    }
+
+    // Same cube of particles with no gravity, water has pressure so it moves, dust doesn't
+    fn speed_after_steps(material: &str) -> f32 {
+        let mut materials = Materials::default();
+        materials.add("dust", Dust);
+        let id = materials.id(material).unwrap();
+
+        let mut world = World::with_bounds(IVec3::ZERO, IVec3::splat(3 * Chunk::width as i32));
+        world.fill_block(Vec3A::splat(8.5), UVec3::splat(8), 1., |x| Particle::new(x, 1., id));
+        let mut sim = Simulation::from_world(world);
+        sim.app.insert_resource(materials).insert_resource(SimParams { gravity: 0., ..Default::default() });
+        sim.run(3);
+        sim.world().max_speed()
+    }
+
+    #[test]
+    fn particles_use_their_material() {
+        assert!(speed_after_steps("dust") < 1e-5);
+        assert!(speed_after_steps("water") > 1e-3);
+    }
+
+    fn fluid_with(C: Mat3A) -> Particle {
+        Particle { C, ..Particle::new(Vec3A::ZERO, 1., MaterialId::water) }
+    }
+
+    #[test]
+    fn fluid_stress_is_newtonian() {
+        let params = SimParams { dynamic_viscosity: 0.5, eos_stiffness: 10., eos_power: 1., ..Default::default() };
+        let rest = params.rest_density;
+        let close = |a: Mat3A, b: Mat3A| a.abs_diff_eq(b, 1e-5);
+
+        // At rest density and not moving there's no stress at all
+        assert!(close(Fluid.stress(&fluid_with(Mat3A::ZERO), rest, &params), Mat3A::ZERO));
+        // Compressed twice over gives k * (2 - 1) pressure, the same on every axis
+        let squeezed = Fluid.stress(&fluid_with(Mat3A::ZERO), 2. * rest, &params);
+        assert!(close(squeezed, Mat3A::from_diagonal(Vec3::splat(-10.))));
+
+        // Spinning isn't deforming so there's nothing to resist
+        let spin = Mat3A::from_cols_array(&[0., 1., 0., -1., 0., 0., 0., 0., 0.]);
+        assert!(close(Fluid.stress(&fluid_with(spin), rest, &params), Mat3A::ZERO));
+        // Simple shear, v.x = y, gets tau = mu * dv.x/dy on both off diagonals
+        let shear = Mat3A::from_cols_array(&[0., 0., 0., 1., 0., 0., 0., 0., 0.]);
+        let stress = Fluid.stress(&fluid_with(shear), rest, &params);
+        assert!(close(stress, Mat3A::from_cols_array(&[0., 0.5, 0., 0.5, 0., 0., 0., 0., 0.])));
+        // Uniform expansion changes the volume, that's the pressure's job not viscosity's
+        let expand = Mat3A::from_diagonal(Vec3::ONE);
+        assert!(close(Fluid.stress(&fluid_with(expand), rest, &params), Mat3A::ZERO));
+    }
+
+    // A settled column of water has to hold up everything above it, so the pressure each particle
+    // gets from the EOS should be g times the mass above it per unit area
+    #[test]
+    fn hydrostatic_column() {
+        let params = SimParams {
+            gravity: -0.3,
+            rest_density: 4.,
+            eos_stiffness: 20.,
+            eos_power: 1.,
+            dynamic_viscosity: 1.,
+            ..Default::default()
+        };
+        // The walls are 2 nodes in on the bottom and 3 on top, so the water fills 2..13 on x and z
+        let (wall_min, wall_max) = (2., 13.);
+        let (height, spacing) = (10., 0.5);
+        // 8 particles a cell so the column starts out at rest density
+        let m = params.rest_density * spacing * spacing * spacing;
+
+        let mut world = World::with_bounds(IVec3::ZERO, IVec3::new(16, 24, 16));
+        let n = ((wall_max - wall_min) / spacing) as u32;
+        let count = UVec3::new(n, (height / spacing) as u32, n);
+        world.fill_block(Vec3A::splat(wall_min + spacing / 2.), count, spacing, |x| Particle::new(x, m, MaterialId::water));
+        let mut sim = Simulation::from_world(world);
+        sim.app.insert_resource(params.clone());
+        // Long enough for the first slosh to die down
+        sim.run(150);
+
+        // The density a particle sees is the grid mass around it, same as in p2g2
+        let world = sim.world();
+        let mut node_mass = HashMap::new();
+        for c in world.chunks.values() {
+            let c = c.lock().unwrap();
+            for (i, node) in c.nodes.iter().enumerate() {
+                node_mass.insert(c.pos + Chunk::node_position(i), node.m);
+            }
+        }
+        // Only looked at away from the walls, and averaged over every particle in a layer one
+        // node thick
+        let particles = world.particles();
+        let inner: Vec<_> = particles.iter()
+            .filter(|p| (5. ..10.).contains(&p.x.x) && (5. ..10.).contains(&p.x.z))
+            .collect();
+        let area = 25.;
+        let mut layers = [(0., 0., 0.); 8];
+        for p in &inner {
+            let (ogn_coord, weights) = stencil_weights(p.x);
+            let mut density = 0.;
+            for gx in 0..3 {
+                for gy in 0..3 {
+                    for gz in 0..3 {
+                        let weight = weights[gx].x * weights[gy].y * weights[gz].z;
+                        let rn_coord = ogn_coord + Vec3A::new(gx as f32 - 1., gy as f32 - 1., gz as f32 - 1.);
+                        density += node_mass.get(&rn_coord.as_ivec3()).copied().unwrap_or(0.) * weight;
+                    }
+                }
+            }
+            let above: f32 = inner.iter().filter(|q| q.x.y > p.x.y).map(|q| q.m).sum();
+            let layer = &mut layers[(p.x.y as usize).min(7)];
+            layer.0 += -Fluid.stress(p, density, &params).x_axis.x;
+            layer.1 += -params.gravity * above / area;
+            layer.2 += 1.;
+        }
+
+        // A particle's density counts some of its own layer which isn't above it, so it's allowed
+        // to be off by about a layer's weight
+        let layer_weight = -params.gravity * m / (spacing * spacing);
+        let mut last = f32::INFINITY;
+        // The bottom two layers are squashed into the wall and the top one is the surface
+        for (pressure, expected, count) in layers[3..7].iter() {
+            let (pressure, expected) = (pressure / count, expected / count);
+            assert!(pressure < last, "pressure goes up with height, {layers:?}");
+            last = pressure;
+            if expected > 2. * layer_weight {
+                assert!((pressure - expected).abs() < 0.1 * expected + layer_weight, "pressure {pressure} but {expected} is above");
+            }
+        }
+    }
+
+    #[test]
+    fn neo_hookean_stress() {
+        let jelly = NeoHookean { youngs_modulus: 30., poisson_ratio: 0.3, density: 4. };
+        let (mu, lambda) = jelly.lame();
+        let params = SimParams::default();
+        let close = |a: Mat3A, b: Mat3A| a.abs_diff_eq(b, 1e-5);
+        let with_F = |F: Mat3A| Particle { F, V0: 0.25, ..Particle::new(Vec3A::ZERO, 1., MaterialId(1)) };
+
+        // Undeformed and rotated are both at rest
+        assert!(close(jelly.stress(&with_F(Mat3A::IDENTITY), 4., &params), Mat3A::ZERO));
+        let turned = with_F(Mat3A::from_rotation_y(1.));
+        assert!(close(jelly.stress(&turned, 4., &params), Mat3A::ZERO));
+        assert!((jelly.volume(&turned, 4.) - 0.25).abs() < 1e-6);
+
+        // Pulled along x, it pulls back hardest along x and the other axes only feel the volume change
+        let pulled = with_F(Mat3A::from_diagonal(Vec3::new(1.2, 1., 1.)));
+        let stress = jelly.stress(&pulled, 4., &params);
+        let lnJ = 1.2_f32.ln();
+        let expected = Vec3::new(mu * (1.44 - 1.) + lambda * lnJ, lambda * lnJ, lambda * lnJ) / 1.2;
+        assert!(close(stress, Mat3A::from_diagonal(expected)));
+        assert!((jelly.volume(&pulled, 4.) - 0.3).abs() < 1e-6);
+    }
+
+    // How wide a cube of the material is after falling onto the floor
+    fn width_after_landing(material: MaterialId) -> (f32, Vec<Particle>) {
+        let mut world = World::with_bounds(IVec3::ZERO, IVec3::splat(24));
+        world.fill_block(Vec3A::splat(10.25), UVec3::splat(8), 0.5, |x| Particle::new(x, 0.5, material));
+        let mut sim = Simulation::from_world(world);
+        sim.run(60);
+        let particles = sim.world().particles();
+        let min = particles.iter().map(|p| p.x.x).fold(f32::INFINITY, f32::min);
+        let max = particles.iter().map(|p| p.x.x).fold(f32::NEG_INFINITY, f32::max);
+        (max - min, particles)
+    }
+
+    #[test]
+    fn jelly_keeps_its_shape() {
+        let (water, _) = width_after_landing(MaterialId::water);
+        let (jelly, particles) = width_after_landing(Materials::default().id("jelly").unwrap());
+        // Started out 3.5 wide between the outer particles
+        assert!(water > 6., "water only spread to {water}");
+        assert!(jelly < 4.5, "jelly spread to {jelly}");
+        assert!(particles.iter().all(|p| p.F.determinant() > 0.5 && p.V0 > 0.));
+    }
+
+    #[test]
+    fn snow_gives_way() {
+        let snow = Snow::default();
+        let params = SimParams::default();
+        let with_F = |F: Mat3A| Particle { F, V0: 0.25, ..Particle::new(Vec3A::ZERO, 1., MaterialId(2)) };
+
+        // A little stretch is elastic and stays in F
+        let mut p = with_F(Mat3A::from_diagonal(Vec3::new(1.005, 1., 1.)));
+        snow.project(&mut p);
+        assert!(p.F.abs_diff_eq(Mat3A::from_diagonal(Vec3::new(1.005, 1., 1.)), 1e-5));
+        assert_eq!(p.Jp, 1.);
+
+        // Past critical stretch it breaks, F keeps the most it can hold and the rest is plastic
+        let mut p = with_F(Mat3A::from_rotation_z(0.3) * Mat3A::from_diagonal(Vec3::new(1.1, 1., 1.)));
+        snow.project(&mut p);
+        let limit = 1. + snow.critical_stretch;
+        assert!((p.F.determinant() - limit).abs() < 1e-4);
+        assert!((p.Jp - 1.1 / limit).abs() < 1e-4);
+        // Still rotated the same way
+        assert!(p.F.abs_diff_eq(Mat3A::from_rotation_z(0.3) * Mat3A::from_diagonal(Vec3::new(limit, 1., 1.)), 1e-4));
+
+        // Squashed too far it packs down and gets harder
+        let mut packed = with_F(Mat3A::from_diagonal(Vec3::new(0.9, 1., 1.)));
+        snow.project(&mut packed);
+        assert!(packed.Jp < 1.);
+        let fresh = with_F(packed.F);
+        let soft = snow.stress(&fresh, 4., &params).x_axis.x;
+        let hard = snow.stress(&packed, 4., &params).x_axis.x;
+        assert!(soft < 0. && hard < soft, "packed {hard} fresh {soft}");
+        assert!(snow.stress(&with_F(Mat3A::IDENTITY), 4., &params).abs_diff_eq(Mat3A::ZERO, 1e-5));
+    }
+
+    #[test]
+    fn snow_packs_when_it_lands() {
+        let (water, _) = width_after_landing(MaterialId::water);
+        let (snow, particles) = width_after_landing(Materials::default().id("snow").unwrap());
+        assert!(snow < water);
+        assert!(particles.iter().any(|p| p.Jp < 0.99));
+        assert!(particles.iter().all(|p| p.Jp.is_finite() && p.F.is_finite()));
+    }
+
+    #[test]
+    fn sand_stays_in_the_cone() {
+        let sand = Sand::default();
+        let (mu, lambda) = sand.lame();
+        let with_F = |F: Mat3A| Particle { F, V0: 0.25, ..Particle::new(Vec3A::ZERO, 1., MaterialId(3)) };
+        let yield_value = |p: &Particle| {
+            let eps = Sand::hencky(crate::svd::svd(p.F).1) + Vec3A::splat(p.Jp.ln() / 3.);
+            let trace = eps.dot(Vec3A::ONE);
+            (eps - Vec3A::splat(trace / 3.)).length() + (3. * lambda + 2. * mu) / (2. * mu) * trace * sand.alpha()
+        };
+
+        // Squashed evenly is fine, it's a pile sitting still
+        let squashed = Mat3A::from_diagonal(Vec3::splat(0.9));
+        let mut p = with_F(squashed);
+        sand.project(&mut p);
+        assert_eq!(p.F, squashed);
+        assert_eq!(p.Jp, 1.);
+
+        // Sheared while squashed it slides, ending up on the edge of the cone with the same pressure
+        let mut p = with_F(Mat3A::from_diagonal(Vec3::new(0.8, 1.1, 0.95)));
+        let trace_before = Sand::hencky(crate::svd::svd(p.F).1).dot(Vec3A::ONE);
+        assert!(yield_value(&p) > 0.);
+        sand.project(&mut p);
+        assert!(yield_value(&p).abs() < 1e-4, "{}", yield_value(&p));
+        assert!((Sand::hencky(crate::svd::svd(p.F).1).dot(Vec3A::ONE) - trace_before).abs() < 1e-4);
+        assert!((p.Jp - 1.).abs() < 1e-4);
+
+        // Pulled apart it lets go completely, and remembers how much room it has to be pushed back
+        let mut p = with_F(Mat3A::from_rotation_x(0.5) * Mat3A::from_diagonal(Vec3::new(1.1, 1., 1.)));
+        sand.project(&mut p);
+        assert!(p.F.abs_diff_eq(Mat3A::from_rotation_x(0.5), 1e-4));
+        assert!((p.Jp - 1.1).abs() < 1e-4);
+        assert!(sand.stress(&p, 4., &SimParams::default()).abs_diff_eq(Mat3A::ZERO, 1e-4));
+        // Squashing it back less than that is still free
+        let mut back = Particle { F: Mat3A::from_diagonal(Vec3::new(0.95, 1., 1.)), ..p };
+        sand.project(&mut back);
+        assert!(back.F.abs_diff_eq(Mat3A::IDENTITY, 1e-4));
+        assert!((back.Jp - 1.1 * 0.95).abs() < 1e-4);
+
+        // Sticky sand holds together up to its cohesion
+        let sticky = Sand { cohesion: 0.05, ..Sand::default() };
+        let pulled = Mat3A::from_diagonal(Vec3::new(1.01, 1., 1.));
+        let mut p = with_F(pulled);
+        sticky.project(&mut p);
+        assert!(p.F.abs_diff_eq(pulled, 1e-5), "{}", p.F);
+    }
+
+    // A column of material standing on the floor, how high it still is after a while
+    fn settle_column(material: MaterialId, floor: Wall, frames: usize) -> Vec<Particle> {
+        let boundary = DomainBoundary::default().with_axis(1, floor);
+        let mut world = World::with_boundary(IVec3::ZERO, IVec3::new(40, 24, 40), boundary).unwrap();
+        world.fill_block(Vec3A::new(18.25, 2.25, 18.25), UVec3::new(8, 16, 8), 0.5, |x| Particle::new(x, 0.5, material));
+        let mut sim = Simulation::from_world(world);
+        sim.run(frames);
+        sim.world().particles()
+    }
+
+    fn column_height(material: MaterialId, floor: Wall) -> f32 {
+        settle_column(material, floor, 20).iter().map(|p| p.x.y).fold(0., f32::max)
+    }
+
+    // Sand can't stand up in a column like jelly can, it slumps down
+    #[test]
+    fn sand_slumps() {
+        let materials = Materials::default();
+        let jelly = column_height(materials.id("jelly").unwrap(), Wall::Slip);
+        let sand = column_height(materials.id("sand").unwrap(), Wall::Slip);
+        assert!(jelly > 9., "jelly fell down to {jelly}");
+        assert!(sand < 5., "sand still stands {sand} high");
+    }
+
+    // On a slippery floor the bottom of the sand slides away and it all spreads out flat, a rough
+    // one holds the bottom in place and leaves a pile
+    #[test]
+    fn sand_piles_on_a_rough_floor() {
+        let sand = Materials::default().id("sand").unwrap();
+        // Mean distance from the middle of the column and the top of what's left
+        let settle = |floor| {
+            let particles = settle_column(sand, floor, 30);
+            let spread = particles.iter().map(|p| Vec2::new(p.x.x - 20., p.x.z - 20.).length()).sum::<f32>() / particles.len() as f32;
+            (spread, particles.iter().map(|p| p.x.y).fold(0., f32::max))
+        };
+        let (slippery_spread, slippery_top) = settle(Wall::Slip);
+        let (rough_spread, rough_top) = settle(Wall::Separate { friction: 0.5 });
+        assert!(rough_spread < slippery_spread * 0.75, "rough floor spread to {rough_spread}, slippery {slippery_spread}");
+        assert!(rough_top > slippery_top + 0.3, "rough floor left {rough_top}, slippery {slippery_top}");
+    }
+
+    #[test]
+    fn viscosity_follows_shear_rate() {
+        let params = SimParams::default();
+        // Simple shear, v.x = rate * y
+        let sheared = |rate: f32| Particle {
+            C: Mat3A::from_cols_array(&[0., 0., 0., rate, 0., 0., 0., 0., 0.]),
+            ..Particle::new(Vec3A::ZERO, 1., MaterialId(1))
+        };
+        let shear_stress = |model: &dyn ConstitutiveModel, rate: f32| {
+            model.stress(&sheared(rate), params.rest_density, &params).y_axis.x
+        };
+
+        // n = 1 and no yield stress is the plain fluid
+        let newtonian = NonNewtonian::power_law(params.dynamic_viscosity, 1.);
+        assert!((shear_stress(&newtonian, 0.7) - shear_stress(&Fluid, 0.7)).abs() < 1e-6);
+
+        // Shear thinning, twice the rate is only 2^n the stress
+        let paint = NonNewtonian::power_law(1., 0.5);
+        let ratio = shear_stress(&paint, 2.) / shear_stress(&paint, 1.);
+        assert!((ratio - 2_f32.sqrt()).abs() < 1e-4);
+
+        // Bingham flows with yield stress + viscosity * rate, below it it's as thick as allowed
+        let mud = NonNewtonian::bingham(0.5, 1.);
+        assert!((shear_stress(&mud, 2.) - (0.5 + 2.)).abs() < 1e-4);
+        assert_eq!(mud.viscosity(0.01), mud.max_viscosity);
+        assert_eq!(mud.viscosity(0.), mud.max_viscosity);
+        assert!((shear_stress(&mud, 0.01) - mud.max_viscosity * 0.01).abs() < 1e-5);
+    }
+
+    // How high a cube of the material sitting on the floor is after a while
+    fn blob_height(model: NonNewtonian) -> f32 {
+        let mut materials = Materials::default();
+        let id = materials.add("blob", model);
+        let mut world = World::with_bounds(IVec3::ZERO, IVec3::new(32, 16, 32));
+        world.fill_block(Vec3A::new(14.25, 2.25, 14.25), UVec3::splat(8), 0.5, |x| Particle::new(x, 0.5, id));
+        let mut sim = Simulation::from_world(world);
+        sim.app.insert_resource(materials);
+        sim.run(20);
+        sim.world().particles().iter().map(|p| p.x.y).fold(0., f32::max)
+    }
+
+    #[test]
+    fn yield_stress_holds_shape() {
+        let runny = blob_height(NonNewtonian::power_law(0.1, 1.));
+        let paste = blob_height(NonNewtonian { max_viscosity: 50., ..NonNewtonian::bingham(5., 1.) });
+        // Started out 5.75 high, the pressure squashes it a bit either way
+        assert!(runny < 4., "runny blob is still {runny} high");
+        assert!(paste > 4.5, "paste blob slumped to {paste}");
+    }
+
+    #[test]
+    fn materials_have_colors() {
+        let mut materials = Materials::default();
+        let water = materials.color(MaterialId::water);
+        let sand = materials.color(materials.id("sand").unwrap());
+        assert_ne!(water, sand);
+
+        let dust = materials.add("dust", Dust);
+        assert_ne!(materials.color(dust), Color::BLACK);
+        materials.set_color(dust, Color::GRAY);
+        // Swapping the model out doesn't repaint it
+        materials.add("dust", Fluid);
+        assert_eq!(materials.color(dust), Color::GRAY);
+    }
+
+    // A heavy jelly cube dropped into a pool of water over a sand bed, the grid sees everyone's
+    // mass so the jelly sinks through the water and stops on the sand
+    #[test]
+    fn mixed_materials_share_a_world() {
+        let materials = Materials::default();
+        let jelly = materials.id("jelly").unwrap();
+        let sand = materials.id("sand").unwrap();
+        let mut world = World::with_bounds(IVec3::ZERO, IVec3::new(16, 24, 16));
+        // The bottom 4 layers are sand
+        world.fill_block(Vec3A::splat(2.25), UVec3::new(24, 16, 24), 0.5, |pos| {
+            Particle::new(pos, 0.5, if pos.y < 4. { sand } else { MaterialId::water })
+        });
+        world.fill_block(Vec3A::new(6.75, 12.25, 6.75), UVec3::splat(6), 0.5, |pos| Particle::new(pos, 2., jelly));
+        let mass = |world: &World| world.particles().iter().map(|p| p.m).sum::<f32>();
+        let before = mass(&world);
+        let mut sim = Simulation::from_world(world);
+        sim.run(150);
+
+        let particles = sim.world().particles();
+        assert!(particles.iter().all(|p| p.x.is_finite() && p.v.is_finite()));
+        assert!((mass(sim.world()) - before).abs() < 1e-3 * before);
+        let heights = |material: MaterialId| particles.iter().filter(|p| p.material == material).map(|p| p.x.y).collect::<Vec<_>>();
+        let (jelly, water, sand) = (heights(jelly), heights(MaterialId::water), heights(sand));
+        let jelly_top = jelly.iter().copied().fold(0., f32::max);
+        let jelly_bottom = jelly.iter().copied().fold(f32::MAX, f32::min);
+        let surface = water.iter().copied().fold(0., f32::max);
+        let sand_level = sand.iter().sum::<f32>() / sand.len() as f32;
+        assert!(jelly_top < surface, "jelly floats up to {jelly_top} over the surface at {surface}");
+        assert!(jelly_bottom > sand_level, "jelly sank into the sand down to {jelly_bottom}");
+    }
+}
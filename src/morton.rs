@@ -1,22 +1,36 @@
-use bevy::math::{Vec3A, UVec3};
+// The masks are grouped by what the bits are for, not by 4
+#![allow(clippy::unusual_byte_groupings)]
+use bevy::math::UVec3;
 
-const BLOCK_MX: u64 =
+// Packed ids keep every 4x4x4 block of nodes together: bits 6 to 11 are the node in its block,
+// 2 bits per axis, and from bit 12 up the morton code of the block
+// These masks are the bits of each axis, they make a packed id a set of dilated integers that
+// can be added without unpacking, see add
+
+/// Every bit of a packed id that belongs to x
+pub const BLOCK_MX: u64 =
 0b0_001_001_001_001_001_001_001_001_001_001_001_001_001_001_001_001_001___000011_0000_00;
-const BLOCK_MY: u64 =
+/// Every bit of a packed id that belongs to y
+pub const BLOCK_MY: u64 =
 0b0_010_010_010_010_010_010_010_010_010_010_010_010_010_010_010_010_010___001100_0000_00;
-const BLOCK_MZ: u64 =
+/// Every bit of a packed id that belongs to z
+pub const BLOCK_MZ: u64 =
 0b0_100_100_100_100_100_100_100_100_100_100_100_100_100_100_100_100_100___110000_0000_00;
+const BLOCK_MASK: u64 = BLOCK_MX | BLOCK_MY | BLOCK_MZ;
 
 const PACK_HEADER: usize = 12;
 const PACK_ALIGN: usize = 6;
-const REGION_ID_MASK: u64 = !0b0___111111111111; // Mask that only retain the region part of the index.
-
+/// Mask that only retains the region part of the index, i.e. which block it's in
+pub const REGION_ID_MASK: u64 = !0b0___111111111111;
 
-const PACKED_NEG_ONE: u64 = 18446744073709551552;
-const PACKED_NEG_TWO: u64 = 18446744073709550208;
-const PACKED_PLUS_FIVE: u64 = 30016;
+/// -1 on every axis, adding it moves one node down x, y and z
+pub const PACKED_NEG_ONE: u64 = 18446744073709551552;
+/// -2 on every axis
+pub const PACKED_NEG_TWO: u64 = 18446744073709550208;
+/// 5 on every axis, one block and one node
+pub const PACKED_PLUS_FIVE: u64 = 30016;
 
-fn spread(mut w: u64) -> u64 {
+const fn spread(mut w: u64) -> u64 {
     w &= 0x00000000001fffff;
     w = (w | w << 32) & 0x001f00000000ffff;
     w = (w | w << 16) & 0x001f0000ff0000ff;
@@ -26,7 +40,7 @@ fn spread(mut w: u64) -> u64 {
     w
 }
 
-fn morton_encode3(x: u32, y: u32, z: u32) -> u64 {
+const fn morton_encode3(x: u32, y: u32, z: u32) -> u64 {
     spread(x as u64) | (spread(y as u64) << 1) | (spread(z as u64) << 2)
 }
 
@@ -47,13 +61,22 @@ fn morton_decode3(code: u64) -> [u32; 3] {
 }
 
 
+// The solver walks stencils one axis at a time with add, these whole-stencil tables and
+// iterators are only checked by the tests
+
+/// The 3x3x3 stencil as packed offsets from its lowest corner, in the same order as NBH_SHIFTS
+#[allow(dead_code)]
 const PACKED_NBH_SHIFTS: [u64; 27] = [
     2688, 2176, 2432, 2560, 2048, 2304, 2624, 2112, 2368, 640, 128, 384, 512, 0, 256, 576, 64, 320,
     1664, 1152, 1408, 1536, 1024, 1280, 1600, 1088, 1344,
 ];
 
+/// The 7 blocks above a block on any of the axes as packed offsets, together with the block
+/// itself they're a 2x2x2 region
+#[allow(dead_code)]
 const PACKED_NBH_REGION_SHIFTS: [u64; 7] = [16384, 8192, 24576, 4096, 20480, 12288, 28672];
 
+#[allow(dead_code)]
 const NBH_SHIFTS: [UVec3; 27] = [
     UVec3::new(2, 2, 2),
     UVec3::new(2, 0, 2),
@@ -85,20 +108,58 @@ const NBH_SHIFTS: [UVec3; 27] = [
 ];
 
 
-#[allow(dead_code)] // Used only to generate the packed offset tables.
-fn packi(x: i32, y: i32, z: i32) -> u64 {
-    // Pack using the 2-complement.
-    pack(x as u32, y as u32, z as u32)
+/// Packs signed coordinates using the 2-complement, each of them has to fit in 19 bits
+pub const fn packi(x: i32, y: i32, z: i32) -> u64 {
+    pack(x as u32, y as u32, z as u32) & BLOCK_MASK
+}
+
+/// Coordinates of a packed id from packi, add or sub
+pub fn unpacki(xyz: u64) -> [i32; 3] {
+    // Each axis is a 19 bit 2-complement number, shifting it to the top of the i32 and back
+    // copies the sign down
+    unpack(xyz & BLOCK_MASK).map(|c| ((c << 13) as i32) >> 13)
+}
+
+/// Adds two packed ids axis by axis. Filling in the bits of the other axes with ones makes the
+/// carries skip over them
+pub fn add(a: u64, b: u64) -> u64 {
+    let axis = |mask: u64| ((a | !mask).wrapping_add(b & mask)) & mask;
+    axis(BLOCK_MX) | axis(BLOCK_MY) | axis(BLOCK_MZ)
+}
+
+/// Subtracts two packed ids axis by axis, the borrows skip over the other axes' bits
+pub fn sub(a: u64, b: u64) -> u64 {
+    let axis = |mask: u64| ((a & mask).wrapping_sub(b & mask)) & mask;
+    axis(BLOCK_MX) | axis(BLOCK_MY) | axis(BLOCK_MZ)
 }
 
+/// Packed id of the block a node is in, which is also the id of the block's lowest node
+pub fn region_id(xyz: u64) -> u64 {
+    xyz & REGION_ID_MASK
+}
 
-pub fn pack(x: u32, y: u32, z: u32) -> u64 {
-    let mut res = morton_encode3(x >> 2, y >> 2, z >> 2) as u64;
+/// The 27 nodes around a node, each with its offset from the lowest of them
+#[allow(dead_code)]
+fn neighbours(xyz: u64) -> impl Iterator<Item = (UVec3, u64)> {
+    let corner = add(xyz, PACKED_NEG_ONE);
+    NBH_SHIFTS.into_iter().zip(PACKED_NBH_SHIFTS).map(move |(shift, packed)| (shift, add(corner, packed)))
+}
 
-    res = res << PACK_HEADER;
-    res = res | ((x as u64 & 0b0011) << PACK_ALIGN);
-    res = res | ((y as u64 & 0b0011) << PACK_ALIGN + 2);
-    res | ((z as u64 & 0b0011) << PACK_ALIGN + 4)
+/// The 8 blocks of the 2x2x2 region whose lowest block is the one the node is in
+#[allow(dead_code)]
+fn region_blocks(xyz: u64) -> impl Iterator<Item = u64> {
+    let region = region_id(xyz);
+    std::iter::once(region).chain(PACKED_NBH_REGION_SHIFTS.into_iter().map(move |shift| add(region, shift)))
+}
+
+
+pub const fn pack(x: u32, y: u32, z: u32) -> u64 {
+    let mut res = morton_encode3(x >> 2, y >> 2, z >> 2);
+
+    res <<= PACK_HEADER;
+    res |= (x as u64 & 0b0011) << PACK_ALIGN;
+    res |= (y as u64 & 0b0011) << (PACK_ALIGN + 2);
+    res | ((z as u64 & 0b0011) << (PACK_ALIGN + 4))
 }
 
 
@@ -113,6 +174,22 @@ pub fn unpack(xyz: u64) -> [u32; 3] {
 }
 
 
+/// Index of a node in an 8x8x8 chunk, which is 2x2x2 blocks of 4x4x4 nodes. Without the alignment
+/// bits the packed ids of its nodes go from 0 to 511 and every block's nodes are next to each other
+pub const fn chunk_index(x: u32, y: u32, z: u32) -> usize {
+    packed_index(pack(x, y, z))
+}
+
+/// A packed id without its alignment bits, for ids of nodes near 0 that's a dense index
+pub const fn packed_index(xyz: u64) -> usize {
+    (xyz >> PACK_ALIGN) as usize
+}
+
+pub fn chunk_position(index: usize) -> [u32; 3] {
+    unpack((index as u64) << PACK_ALIGN)
+}
+
+
 #[test]
 fn test_morton() {
     for i in 0..100 {
@@ -127,5 +204,76 @@ fn test_morton() {
     }
 }
 
+#[test]
+fn test_chunk_index() {
+    let mut seen = [false; 512];
+    for x in 0..8 {
+        for y in 0..8 {
+            for z in 0..8 {
+                let index = chunk_index(x, y, z);
+                assert!(!seen[index]);
+                seen[index] = true;
+                assert_eq!(chunk_position(index), [x, y, z]);
+                // The first block is the first 64 nodes
+                assert_eq!(index < 64, x < 4 && y < 4 && z < 4);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_signed_morton() {
+    for x in [-150000, -9, -5, -4, -1, 0, 3, 4, 262143] {
+        for y in [-262144, -2, 0, 1, 7] {
+            for z in [-8, -3, 0, 5, 100] {
+                assert_eq!(unpacki(packi(x, y, z)), [x, y, z]);
+            }
+        }
+    }
+    assert_eq!(unpacki(PACKED_NEG_ONE), [-1; 3]);
+    assert_eq!(unpacki(PACKED_NEG_TWO), [-2; 3]);
+    assert_eq!(PACKED_PLUS_FIVE, pack(5, 5, 5));
+    // The masks cover every bit of a packed id but the unused low ones, and don't overlap
+    assert_eq!(BLOCK_MX & BLOCK_MY, 0);
+    assert_eq!(BLOCK_MX | BLOCK_MY | BLOCK_MZ | 0b111111, !0 >> 1);
+}
+
+#[test]
+fn test_packed_arithmetic() {
+    let coords = [-13, -4, -1, 0, 2, 3, 4, 7, 61];
+    for &x in &coords {
+        for &y in &coords {
+            for &z in &coords {
+                let a = packi(x, y, z);
+                assert_eq!(unpacki(add(a, packi(3, -5, 9))), [x + 3, y - 5, z + 9]);
+                assert_eq!(unpacki(sub(a, packi(3, -5, 9))), [x - 3, y + 5, z - 9]);
+                assert_eq!(unpacki(add(a, PACKED_NEG_ONE)), [x - 1, y - 1, z - 1]);
+                assert_eq!(unpacki(add(a, PACKED_PLUS_FIVE)), [x + 5, y + 5, z + 5]);
+                assert_eq!(sub(add(a, PACKED_NEG_TWO), PACKED_NEG_TWO), a);
+                // The block is the coordinates rounded down to a multiple of 4
+                assert_eq!(unpacki(region_id(a)), [x & !3, y & !3, z & !3]);
+            }
+        }
+    }
+}
 
+#[test]
+fn test_neighbours() {
+    let node = packi(4, -1, 7);
+    let around: Vec<_> = neighbours(node).collect();
+    assert_eq!(around.len(), 27);
+    for (shift, packed) in around {
+        let [x, y, z] = shift.as_ivec3().to_array();
+        assert_eq!(unpacki(packed), [3 + x, -2 + y, 6 + z]);
+    }
 
+    let blocks: Vec<_> = region_blocks(packi(5, 2, -3)).map(unpacki).collect();
+    assert_eq!(blocks.len(), 8);
+    for x in [4, 8] {
+        for y in [0, 4] {
+            for z in [-4, 0] {
+                assert!(blocks.contains(&[x, y, z]));
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Everything the solver reads that you might want to tweak without recompiling
+/// Missing fields in a file fall back to their defaults
+#[derive(Resource, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimParams {
+    /// Acceleration along y in nodes per time unit squared
+    pub gravity: f32,
+    pub rest_density: f32,
+    pub dynamic_viscosity: f32,
+    pub eos_stiffness: f32,
+    pub eos_power: f32,
+    /// Most nodes anything can travel in one substep at the finest level
+    pub cfl: f32,
+    pub min_dt: f32,
+    pub max_dt: f32,
+    /// Simulation time to advance every frame
+    pub frame_time: f32,
+    /// Steps between sorting the particles of every chunk by the node they're in, which keeps
+    /// the transfers going through memory in order as particles move. Counts `World::steps`, so
+    /// a frame can sort more than once. 0 never sorts
+    pub sort_every: u32,
+}
+
+impl Default for SimParams {
+    fn default() -> Self {
+        SimParams {
+            gravity: -0.3,
+            rest_density: 4.0,
+            dynamic_viscosity: 0.1,
+            eos_stiffness: 10.0,
+            eos_power: 4.,
+            cfl: 0.5,
+            min_dt: 0.01,
+            max_dt: 0.4,
+            frame_time: 0.4,
+            sort_every: 4,
+        }
+    }
+}
+
+impl SimParams {
+    /// Reads params from a RON file and checks they make sense
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("couldn't read {}: {e}", path.display()))?;
+        SimParams::from_ron(&text)
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))
+    }
+
+    pub fn from_ron(text: &str) -> anyhow::Result<Self> {
+        let params: SimParams = ron::from_str(text)?;
+        params.validate()?;
+        Ok(params)
+    }
+
+    pub fn to_ron(&self) -> String {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).unwrap()
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let fields = [
+            ("gravity", self.gravity),
+            ("rest_density", self.rest_density),
+            ("dynamic_viscosity", self.dynamic_viscosity),
+            ("eos_stiffness", self.eos_stiffness),
+            ("eos_power", self.eos_power),
+            ("cfl", self.cfl),
+            ("min_dt", self.min_dt),
+            ("max_dt", self.max_dt),
+            ("frame_time", self.frame_time),
+        ];
+        for (name, value) in fields {
+            anyhow::ensure!(value.is_finite(), "{name} has to be a number, got {value}");
+        }
+        anyhow::ensure!(self.rest_density > 0., "rest_density has to be positive, got {}", self.rest_density);
+        anyhow::ensure!(self.dynamic_viscosity >= 0., "dynamic_viscosity can't be negative, got {}", self.dynamic_viscosity);
+        anyhow::ensure!(self.eos_stiffness >= 0., "eos_stiffness can't be negative, got {}", self.eos_stiffness);
+        anyhow::ensure!(self.eos_power > 0., "eos_power has to be positive, got {}", self.eos_power);
+        anyhow::ensure!(self.cfl > 0., "cfl has to be positive, got {}", self.cfl);
+        anyhow::ensure!(self.min_dt > 0., "min_dt has to be positive, got {}", self.min_dt);
+        anyhow::ensure!(self.min_dt <= self.max_dt, "min_dt {} is bigger than max_dt {}", self.min_dt, self.max_dt);
+        anyhow::ensure!(self.frame_time >= 0., "frame_time can't be negative, got {}", self.frame_time);
+        Ok(())
+    }
+
+    /// Speed of sound in the fluid at rest density, sqrt(dp/drho) of the EOS
+    pub fn wave_speed(&self) -> f32 {
+        (self.eos_stiffness * self.eos_power / self.rest_density).sqrt()
+    }
+}
+
+/// Keeps whatever the params get changed to at runtime sane, bad changes are logged and rolled
+/// back to the last good params
+pub fn check_params(
+    mut params: ResMut<SimParams>,
+    mut last_good: Local<Option<SimParams>>,
+) {
+    if !params.is_changed() {
+        return;
+    }
+    match params.validate() {
+        Ok(()) => *last_good = Some(params.clone()),
+        Err(e) => {
+            error!("Invalid simulation params, keeping the old ones: {e}");
+            *params = last_good.clone().unwrap_or_default();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SimParams;
+
+    #[test]
+    fn round_trips_through_ron() {
+        let params = SimParams { gravity: -1., ..Default::default() };
+        assert_eq!(SimParams::from_ron(&params.to_ron()).unwrap(), params);
+
+        // Missing fields are defaults
+        let partial = SimParams::from_ron("(rest_density: 2.0)").unwrap();
+        assert_eq!(partial, SimParams { rest_density: 2., ..Default::default() });
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        assert!(SimParams::from_ron("(rest_density: -1.0)").is_err());
+        assert!(SimParams::from_ron("(min_dt: 0.0)").is_err());
+        assert!(SimParams::from_ron("(min_dt: 1.0, max_dt: 0.5)").is_err());
+        assert!(SimParams::from_ron("(not_a_field: 1.0)").is_err());
+        assert!(SimParams::from_ron("(gravity: \"down\")").is_err());
+        let err = SimParams::from_ron("(eos_power: 0.0)").unwrap_err();
+        assert!(err.to_string().contains("eos_power"));
+    }
+}
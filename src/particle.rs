@@ -1,4 +1,5 @@
 use bevy::{prelude::*, math::{Vec3A, Mat3A}};
+use crate::material::MaterialId;
 
 #[derive(Component, Clone, Copy, Debug)]
 pub struct Particle {
@@ -6,4 +7,15 @@ pub struct Particle {
     pub v: Vec3A,    // velocity
     pub C: Mat3A,     // affine momentum matrix
     pub m: f32,     // mass
+    pub material: MaterialId,
+    pub F: Mat3A,   // deformation gradient
+    pub V0: f32,    // volume at rest, 0 until the first p2g2 fills it in from the grid
+    pub Jp: f32,    // how much plastic flow has changed the volume, for materials that give way
+}
+
+impl Particle {
+    /// A particle at rest and undeformed
+    pub fn new(x: Vec3A, m: f32, material: MaterialId) -> Self {
+        Particle { x, v: Vec3A::ZERO, C: Mat3A::ZERO, m, material, F: Mat3A::IDENTITY, V0: 0., Jp: 1. }
+    }
 }
@@ -0,0 +1,339 @@
+use bevy::{prelude::*, math::Vec3A};
+use std::sync::Mutex;
+use crate::{collider::{Boundary, Collider, Colliders, Geometry}, params::SimParams, world::World};
+
+/// A prop the material pushes around and that pushes back, put it on an entity with a
+/// `Transform`. The translation is its center of mass and the geometry is around it
+#[derive(Component, Debug, Clone)]
+pub struct MpmRigidBody {
+    pub geometry: Geometry,
+    pub mass: f32,
+    /// Moments of inertia around the body's own axes
+    pub inertia: Vec3A,
+    pub velocity: Vec3A,
+    /// Axis times radians per unit of time
+    pub angular_velocity: Vec3A,
+    /// How the material meets its surface
+    pub boundary: Boundary,
+    /// Coulomb friction against the solid domain walls
+    pub friction: f32,
+}
+
+impl MpmRigidBody {
+    pub fn new(geometry: Geometry, mass: f32, inertia: Vec3A) -> Self {
+        MpmRigidBody {
+            geometry,
+            mass,
+            inertia,
+            velocity: Vec3A::ZERO,
+            angular_velocity: Vec3A::ZERO,
+            boundary: Boundary::Slip,
+            friction: 0.5,
+        }
+    }
+
+    /// Mass and inertia of the shape filled with something this dense. Meshes are summed up
+    /// from their distance field and recentred on their center of mass, planes can't be moved
+    pub fn solid(mut geometry: Geometry, density: f32) -> Self {
+        // The body turns around its center of mass, so that's where the mesh's origin has to be
+        if let Geometry::Mesh(sdf) = &mut geometry {
+            sdf.translate(-sdf.centroid());
+        }
+        let (mass, inertia) = match &geometry {
+            Geometry::Box { half_extents: h } => {
+                let mass = density * 8. * h.x * h.y * h.z;
+                let h2 = *h * *h;
+                (mass, mass / 3. * Vec3A::new(h2.y + h2.z, h2.x + h2.z, h2.x + h2.y))
+            }
+            Geometry::Sphere { radius } => {
+                let mass = density * 4. / 3. * std::f32::consts::PI * radius.powi(3);
+                (mass, Vec3A::splat(0.4 * mass * radius * radius))
+            }
+            // A cylinder plus the two halves of a sphere moved out to its ends, a half sphere's
+            // middle is 3/8 r from its flat side and it has 83/320 m r^2 around there
+            Geometry::Capsule { half_height: h, radius: r } => {
+                let cylinder = density * std::f32::consts::PI * r * r * 2. * h;
+                let caps = density * 4. / 3. * std::f32::consts::PI * r.powi(3);
+                let around = 0.5 * cylinder * r * r + 0.4 * caps * r * r;
+                let across = cylinder * (3. * r * r + 4. * h * h) / 12. + caps * (83. / 320. * r * r + (h + 0.375 * r).powi(2));
+                (cylinder + caps, Vec3A::new(across, around, across))
+            }
+            Geometry::Mesh(sdf) => sdf.inside().fold((0., Vec3A::ZERO), |(mass, inertia), (p, volume)| {
+                let m = density * volume;
+                let p2 = p * p;
+                (mass + m, inertia + m * Vec3A::new(p2.y + p2.z, p2.x + p2.z, p2.x + p2.y))
+            }),
+            Geometry::Plane { .. } => (f32::INFINITY, Vec3A::splat(f32::INFINITY)),
+        };
+        MpmRigidBody::new(geometry, mass, inertia)
+    }
+
+    // Inverse of the inertia turned into the world, applied to a vector
+    fn inverse_inertia(&self, rotation: Quat, v: Vec3A) -> Vec3A {
+        rotation * ((rotation.inverse() * v) / self.inertia)
+    }
+
+    // Hits the body with an impulse at r from its center
+    fn apply_impulse(&mut self, rotation: Quat, r: Vec3A, impulse: Vec3A) {
+        self.velocity += impulse / self.mass;
+        self.angular_velocity += self.inverse_inertia(rotation, r.cross(impulse));
+    }
+
+    // Impulse along dir at r that changes the velocity of that point along dir by dv
+    fn impulse_for(&self, rotation: Quat, r: Vec3A, dir: Vec3A, dv: f32) -> f32 {
+        let angular = self.inverse_inertia(rotation, r.cross(dir)).cross(r).dot(dir);
+        dv / (1. / self.mass + angular)
+    }
+}
+
+/// The rigid bodies as colliders for the step being taken, filled in from the entities before
+/// the step and holding the momentum the grid gave them until it's handed back after
+#[derive(Resource, Default)]
+pub struct RigidBodies {
+    entities: Vec<Entity>,
+    pub colliders: Colliders,
+    // Linear and angular momentum taken from the grid by every body
+    impulses: Mutex<Vec<(Vec3A, Vec3A)>>,
+}
+
+impl RigidBodies {
+    /// Velocity of a node at x with mass m after running into the bodies, the momentum it
+    /// loses is added to impulses, which has a slot per body
+    pub fn collide(&self, x: Vec3A, m: f32, mut v: Vec3A, impulses: &mut [(Vec3A, Vec3A)]) -> Vec3A {
+        for (collider, impulse) in self.colliders.0.iter().zip(impulses.iter_mut()) {
+            let before = v;
+            v = collider.collide(x, v);
+            let taken = m * (before - v);
+            impulse.0 += taken;
+            impulse.1 += (x - collider.position).cross(taken);
+        }
+        v
+    }
+
+    /// Adds up what one chunk's nodes gave the bodies
+    pub fn add_impulses(&self, impulses: &[(Vec3A, Vec3A)]) {
+        let mut total = self.impulses.lock().unwrap();
+        for (total, impulse) in total.iter_mut().zip(impulses) {
+            total.0 += impulse.0;
+            total.1 += impulse.1;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// Fastest any point of a body is moving, so dt can keep up with them
+    pub fn max_speed(&self) -> f32 {
+        self.colliders.0.iter().map(|c| {
+            let reach = c.geometry.support(c.angular_velocity).length().max(c.geometry.support(-c.angular_velocity).length());
+            c.velocity.length() + c.angular_velocity.length() * reach
+        }).fold(0., f32::max)
+    }
+}
+
+// Turns every body into a collider where it is now, for update_grid to use this step
+pub(crate) fn gather_bodies(world: &mut bevy::ecs::world::World) {
+    let mut query = world.query::<(Entity, &MpmRigidBody, &Transform)>();
+    let (entities, colliders): (Vec<_>, Vec<_>) = query.iter(world).map(|(entity, body, transform)| {
+        (entity, Collider {
+            rotation: transform.rotation,
+            velocity: body.velocity,
+            angular_velocity: body.angular_velocity,
+            ..Collider::new(body.geometry.clone(), transform.translation.into(), body.boundary)
+        })
+    }).unzip();
+    let len = entities.len();
+    world.insert_resource(RigidBodies {
+        entities,
+        colliders: Colliders(colliders),
+        impulses: Mutex::new(vec![(Vec3A::ZERO, Vec3A::ZERO); len]),
+    });
+}
+
+// Gives the bodies what the grid pushed them with, adds gravity, keeps them inside the domain
+// walls and moves them along by dt
+pub(crate) fn step_bodies(world: &mut bevy::ecs::world::World, dt: f32) {
+    let gravity = world.resource::<SimParams>().gravity;
+    let bounds = world.resource::<World>().bounds;
+    let boundary = world.resource::<World>().boundary;
+    let bodies = world.resource::<RigidBodies>();
+    let impulses: Vec<_> = bodies.entities.iter().copied().zip(bodies.impulses.lock().unwrap().iter().copied()).collect();
+
+    for (entity, (linear, angular)) in impulses {
+        let Some(mut entity) = world.get_entity_mut(entity) else {
+            continue;
+        };
+        let Some(mut transform) = entity.get_mut::<Transform>().map(|t| *t) else {
+            continue;
+        };
+        let Some(mut body) = entity.get_mut::<MpmRigidBody>() else {
+            continue;
+        };
+        let body = &mut *body;
+        // Planes and anything else with infinite mass stay where they were put
+        if !body.mass.is_finite() {
+            continue;
+        }
+        let rotation = transform.rotation;
+        body.velocity += linear / body.mass;
+        body.angular_velocity += body.inverse_inertia(rotation, angular);
+        body.velocity.y += dt * gravity;
+
+        if let Some((min, max)) = bounds {
+            // Same walls the particles get pushed back from, bodies go straight through
+            // periodic and outflow faces
+            for axis in 0..3 {
+                let n = Vec3A::AXES[axis];
+                if boundary.min[axis].solid().is_some() {
+                    collide_with_wall(body, &mut transform, n, min[axis] as f32 + 2., dt);
+                }
+                if boundary.max[axis].solid().is_some() {
+                    collide_with_wall(body, &mut transform, -n, 3. - max[axis] as f32, dt);
+                }
+            }
+        }
+
+        transform.translation += Vec3::from(body.velocity * dt);
+        transform.rotation = (Quat::from_scaled_axis((body.angular_velocity * dt).into()) * transform.rotation).normalize();
+        *entity.get_mut::<Transform>().unwrap() = transform;
+    }
+}
+
+// The wall is everything with x.n < offset. The deepest point of the body gets stopped from going
+// further in and slowed by friction, then the body's moved back out
+fn collide_with_wall(body: &mut MpmRigidBody, transform: &mut Transform, n: Vec3A, offset: f32, dt: f32) {
+    let rotation = transform.rotation;
+    let position = Vec3A::from(transform.translation);
+    let r = rotation * body.geometry.support(rotation.inverse() * -n);
+    // Where it'll be at the end of the step
+    let depth = offset - (position + r + body.velocity * dt).dot(n);
+    if depth <= 0. {
+        return;
+    }
+    let point_v = body.velocity + body.angular_velocity.cross(r);
+    let normal_v = point_v.dot(n);
+    if normal_v < 0. {
+        let j = body.impulse_for(rotation, r, n, -normal_v);
+        body.apply_impulse(rotation, r, j * n);
+
+        let point_v = body.velocity + body.angular_velocity.cross(r);
+        let tangent_v = point_v - point_v.dot(n) * n;
+        let speed = tangent_v.length();
+        if speed > 0. {
+            let dir = tangent_v / speed;
+            let stop = body.impulse_for(rotation, r, dir, speed);
+            body.apply_impulse(rotation, r, -stop.min(body.friction * j) * dir);
+        }
+    }
+    // Whatever's still in after that gets pushed out
+    let depth = offset - (position + r + body.velocity * dt).dot(n);
+    if depth > 0. {
+        transform.translation += Vec3::from(depth * n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{prelude::*, math::Vec3A};
+    use crate::{collider::{box_triangles, Geometry, MeshSdf}, material::MaterialId, params::SimParams, particle::Particle, simulation::Simulation, world::World};
+    use super::MpmRigidBody;
+
+    #[test]
+    fn solid_mass_and_inertia() {
+        let cube = MpmRigidBody::solid(Geometry::Box { half_extents: Vec3A::new(1., 2., 3.) }, 2.);
+        assert!((cube.mass - 96.).abs() < 1e-3);
+        assert!((cube.inertia.x - 96. / 3. * 13.).abs() < 1e-2);
+        let ball = MpmRigidBody::solid(Geometry::Sphere { radius: 1. }, 3. / (4. * std::f32::consts::PI));
+        assert!((ball.mass - 1.).abs() < 1e-5 && (ball.inertia.y - 0.4).abs() < 1e-5);
+        // No length and it's a sphere
+        let capsule = MpmRigidBody::solid(Geometry::Capsule { half_height: 0., radius: 1. }, 1.);
+        let sphere = MpmRigidBody::solid(Geometry::Sphere { radius: 1. }, 1.);
+        assert!((capsule.mass - sphere.mass).abs() < 1e-4);
+        assert!((capsule.inertia - sphere.inertia).abs().max_element() < 1e-4);
+    }
+
+    // A 4 wide cube that's nowhere near the OBJ origin turns around its own middle
+    #[test]
+    fn meshes_are_recentred() {
+        let triangles = box_triangles(Vec3::new(10., 0., 6.), 4.);
+        let body = MpmRigidBody::solid(Geometry::Mesh(MeshSdf::new(&triangles, 0.5)), 1.);
+        // The samples inside are 7 a side half a node apart, so 1 out from the middle on average
+        // along each axis
+        assert!((body.inertia - Vec3A::splat(2. * body.mass)).abs().max_element() < 1e-3 * body.mass, "{}", body.inertia);
+        assert!((body.geometry.distance(Vec3A::ZERO) + 2.).abs() < 0.3);
+        assert!((body.geometry.support(Vec3A::ONE) - Vec3A::splat(2.)).abs().max_element() < 0.3);
+    }
+
+    // A box dropped into a pool of water, how high its middle ends up and where the water's
+    // surface is
+    fn settles_at(density: f32) -> (f32, f32) {
+        let mut world = World::with_bounds(IVec3::ZERO, IVec3::new(16, 24, 16));
+        world.fill_block(Vec3A::splat(2.25), UVec3::new(22, 14, 22), 0.5, |x| Particle::new(x, 0.5, MaterialId::water));
+        let mut sim = Simulation::from_world(world);
+        let body = sim.app.world.spawn((
+            MpmRigidBody::solid(Geometry::Box { half_extents: Vec3A::splat(1.5) }, density),
+            Transform::from_xyz(7.5, 12., 7.5),
+        )).id();
+        sim.run(150);
+        let surface = sim.world().particles().iter().map(|p| p.x.y).fold(0., f32::max);
+        (sim.app.world.get::<Transform>(body).unwrap().translation.y, surface)
+    }
+
+    // Water's 4 dense, a quarter of that floats with its top out of the water and four times
+    // that sinks to the floor
+    #[test]
+    fn light_bodies_float_heavy_ones_sink() {
+        let (light, surface) = settles_at(1.);
+        assert!(light + 1.5 > surface, "light box sank to {light} under the surface at {surface}");
+        let (heavy, _) = settles_at(16.);
+        assert!(heavy < 4., "heavy box floats at {heavy}");
+        assert!(heavy > 3.4, "heavy box went through the floor to {heavy}");
+    }
+
+    // A box thrown through a blob of water with nothing else around, what the water gains the
+    // box loses
+    #[test]
+    fn momentum_goes_both_ways() {
+        let mut world = World::new();
+        world.fill_block(Vec3A::splat(10.25), UVec3::splat(12), 0.5, |x| Particle::new(x, 0.5, MaterialId::water));
+        let mut sim = Simulation::from_world(world);
+        sim.app.insert_resource(SimParams { gravity: 0., ..Default::default() });
+        let body = sim.app.world.spawn((
+            MpmRigidBody { velocity: Vec3A::new(2., 0., 0.), ..MpmRigidBody::solid(Geometry::Sphere { radius: 1.5 }, 4.) },
+            Transform::from_xyz(6., 13., 13.),
+        )).id();
+        let momentum = |sim: &Simulation| {
+            let body = sim.app.world.get::<MpmRigidBody>(body).unwrap();
+            sim.world().particles().iter().fold(body.mass * body.velocity, |acc, p| acc + p.m * p.v)
+        };
+        let before = momentum(&sim);
+        sim.run(20);
+        let after = momentum(&sim);
+
+        let body = sim.app.world.get::<MpmRigidBody>(body).unwrap();
+        assert!(body.velocity.x < 1.9, "box went through without slowing down");
+        assert!((after - before).length() < 1e-2 * before.length(), "momentum went from {before} to {after}");
+    }
+
+    // Infinite mass doesn't fall or get knocked about by the floor it's sitting in
+    #[test]
+    fn planes_stay_put() {
+        let mut world = World::with_bounds(IVec3::ZERO, IVec3::splat(24));
+        world.add_particle(Particle::new(Vec3A::new(12., 8., 12.), 1., MaterialId::water));
+        let mut sim = Simulation::from_world(world);
+        let plane = sim.app.world.spawn((
+            MpmRigidBody::solid(Geometry::Plane { normal: Vec3A::Y }, 1.),
+            Transform::from_xyz(12., 4., 12.),
+        )).id();
+        sim.run(40);
+        let body = sim.app.world.get::<MpmRigidBody>(plane).unwrap();
+        assert_eq!(body.velocity, Vec3A::ZERO);
+        assert_eq!(body.angular_velocity, Vec3A::ZERO);
+        assert_eq!(sim.app.world.get::<Transform>(plane).unwrap().translation, Vec3::new(12., 4., 12.));
+    }
+}
@@ -0,0 +1,515 @@
+use bevy::{prelude::*, math::Vec3A};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use crate::{boundary::DomainBoundary, collider::{Boundary, Collider, Colliders, Geometry, MeshSdf}, material::Materials, params::SimParams, particle::Particle, world::World};
+
+/// Initial conditions read from a RON file, blocks of material filled with particles
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MpmScene {
+    /// Walls of the simulation in nodes, leave it out for an unbounded world
+    #[serde(default)]
+    pub domain: Option<Domain>,
+    /// Replaces the `SimParams` resource when the scene is loaded
+    #[serde(default)]
+    pub params: Option<SimParams>,
+    pub blocks: Vec<Block>,
+    /// Solids in the way of the particles, replaces the `Colliders` resource when loaded
+    #[serde(default)]
+    pub colliders: Vec<ColliderBlock>,
+    /// Where mesh paths are looked up from, the scene file's folder
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Domain {
+    pub min: [i32; 3],
+    pub max: [i32; 3],
+    /// What each face does, slip walls all round if it's left out
+    #[serde(default)]
+    pub boundary: DomainBoundary,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Block {
+    pub shape: Shape,
+    #[serde(default)]
+    pub velocity: [f32; 3],
+    /// Mass of each particle
+    #[serde(default = "one")]
+    pub mass: f32,
+    /// Name of the material in `Materials`
+    #[serde(default = "water")]
+    pub material: String,
+    /// Distance between particles, 0.5 puts 8 in every cell
+    #[serde(default = "one")]
+    pub spacing: f32,
+}
+
+fn one() -> f32 {
+    1.
+}
+
+fn water() -> String {
+    "water".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Shape {
+    Box {
+        min: [f32; 3],
+        max: [f32; 3],
+    },
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+    },
+    /// axis goes from the center of the base to the center of the top
+    Cylinder {
+        base: [f32; 3],
+        axis: [f32; 3],
+        radius: f32,
+    },
+    /// Closed triangle mesh from an OBJ file, scaled and then moved by offset
+    Mesh {
+        path: PathBuf,
+        #[serde(default)]
+        offset: [f32; 3],
+        #[serde(default = "one")]
+        scale: f32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColliderBlock {
+    pub shape: ColliderShape,
+    pub position: [f32; 3],
+    /// Axis times the angle in radians it's turned by
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    /// Moves it every step, leave it out for a static collider
+    #[serde(default)]
+    pub velocity: [f32; 3],
+    #[serde(default)]
+    pub angular_velocity: [f32; 3],
+    pub boundary: Boundary,
+}
+
+/// Same as `Geometry`, in the collider's own frame
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ColliderShape {
+    Plane {
+        normal: [f32; 3],
+    },
+    Box {
+        half_extents: [f32; 3],
+    },
+    Sphere {
+        radius: f32,
+    },
+    Capsule {
+        half_height: f32,
+        radius: f32,
+    },
+    /// Closed triangle mesh from an OBJ file, scaled and then turned into a distance field
+    /// sampled every spacing
+    Mesh {
+        path: PathBuf,
+        #[serde(default = "one")]
+        scale: f32,
+        #[serde(default = "half")]
+        spacing: f32,
+    },
+}
+
+fn half() -> f32 {
+    0.5
+}
+
+/// Resource pointing at a scene file, `MpmPlugin` loads it into `World` before `Startup`
+#[derive(Resource, Debug, Clone)]
+pub struct SceneFile(pub PathBuf);
+
+impl MpmScene {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("couldn't read {}: {e}", path.display()))?;
+        let mut scene = MpmScene::from_ron(&text)
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        scene.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(scene)
+    }
+
+    pub fn from_ron(text: &str) -> anyhow::Result<Self> {
+        let scene: MpmScene = ron::from_str(text)?;
+        if let Some(params) = &scene.params {
+            params.validate()?;
+        }
+        Ok(scene)
+    }
+
+    /// Fills a new world with the scene's particles, material names are looked up in `materials`
+    pub fn build(&self, materials: &Materials) -> anyhow::Result<World> {
+        let mut world = match &self.domain {
+            Some(domain) => World::with_boundary(IVec3::from_array(domain.min), IVec3::from_array(domain.max), domain.boundary)?,
+            None => World::new(),
+        };
+        for (i, block) in self.blocks.iter().enumerate() {
+            self.fill(&mut world, block, materials)
+                .map_err(|e| anyhow::anyhow!("block {i}: {e}"))?;
+        }
+        Ok(world)
+    }
+
+    /// Builds the scene's colliders, meshes get their distance fields sampled here
+    pub fn colliders(&self) -> anyhow::Result<Colliders> {
+        self.colliders.iter().enumerate().map(|(i, block)| {
+            self.collider(block).map_err(|e| anyhow::anyhow!("collider {i}: {e}"))
+        }).collect::<anyhow::Result<_>>().map(Colliders)
+    }
+
+    fn collider(&self, block: &ColliderBlock) -> anyhow::Result<Collider> {
+        let geometry = match &block.shape {
+            ColliderShape::Plane { normal } => {
+                let normal = Vec3A::from_array(*normal);
+                anyhow::ensure!(normal.length() > 0., "plane normal can't be zero");
+                Geometry::Plane { normal: normal.normalize() }
+            }
+            ColliderShape::Box { half_extents } => Geometry::Box { half_extents: Vec3A::from_array(*half_extents) },
+            ColliderShape::Sphere { radius } => Geometry::Sphere { radius: *radius },
+            ColliderShape::Capsule { half_height, radius } => Geometry::Capsule { half_height: *half_height, radius: *radius },
+            ColliderShape::Mesh { path, scale, spacing } => {
+                anyhow::ensure!(*spacing > 0., "spacing has to be positive, got {spacing}");
+                let triangles: Vec<_> = load_obj(&self.base_dir.join(path))?.into_iter()
+                    .map(|tri| tri.map(|v| v * *scale))
+                    .collect();
+                Geometry::Mesh(MeshSdf::new(&triangles, *spacing))
+            }
+        };
+        Ok(Collider {
+            rotation: Quat::from_scaled_axis(Vec3::from_array(block.rotation)),
+            velocity: Vec3A::from_array(block.velocity),
+            angular_velocity: Vec3A::from_array(block.angular_velocity),
+            ..Collider::new(geometry, Vec3A::from_array(block.position), block.boundary)
+        })
+    }
+
+    fn fill(&self, world: &mut World, block: &Block, materials: &Materials) -> anyhow::Result<()> {
+        anyhow::ensure!(block.mass > 0., "mass has to be positive, got {}", block.mass);
+        anyhow::ensure!(block.spacing > 0., "spacing has to be positive, got {}", block.spacing);
+        let material = materials.id(&block.material).ok_or_else(|| anyhow::anyhow!(
+            "unknown material {:?}, there's {:?}", block.material, materials.names().collect::<Vec<_>>()
+        ))?;
+
+        let shape = Solid::new(&block.shape, &self.base_dir)?;
+        let (min, max) = shape.bounds();
+        let count = ((max - min) / block.spacing).ceil().as_uvec3();
+        for x in 0..count.x {
+            for y in 0..count.y {
+                for z in 0..count.z {
+                    let pos = min + (Vec3::new(x as f32, y as f32, z as f32) + 0.5) * block.spacing;
+                    if !shape.contains(pos) {
+                        continue;
+                    }
+                    world.add_particle(Particle {
+                        v: Vec3A::from_array(block.velocity),
+                        // Every particle stands for a spacing sized cube of the block
+                        V0: block.spacing.powi(3),
+                        ..Particle::new(pos.into(), block.mass, material)
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// A shape that's ready to be tested against, meshes have their triangles loaded
+enum Solid {
+    Box(Vec3, Vec3),
+    Sphere(Vec3, f32),
+    Cylinder(Vec3, Vec3, f32),
+    Mesh(Vec<[Vec3; 3]>),
+}
+
+impl Solid {
+    fn new(shape: &Shape, base_dir: &Path) -> anyhow::Result<Self> {
+        let solid = match shape {
+            Shape::Box { min, max } => Solid::Box(Vec3::from_array(*min), Vec3::from_array(*max)),
+            Shape::Sphere { center, radius } => Solid::Sphere(Vec3::from_array(*center), *radius),
+            Shape::Cylinder { base, axis, radius } => Solid::Cylinder(Vec3::from_array(*base), Vec3::from_array(*axis), *radius),
+            Shape::Mesh { path, offset, scale } => {
+                let path = base_dir.join(path);
+                let triangles = load_obj(&path)?.into_iter()
+                    .map(|tri| tri.map(|v| v * *scale + Vec3::from_array(*offset)))
+                    .collect();
+                Solid::Mesh(triangles)
+            }
+        };
+        Ok(solid)
+    }
+
+    fn bounds(&self) -> (Vec3, Vec3) {
+        match self {
+            Solid::Box(min, max) => (*min, *max),
+            Solid::Sphere(center, radius) => (*center - *radius, *center + *radius),
+            Solid::Cylinder(base, axis, radius) => {
+                let top = *base + *axis;
+                (base.min(top) - *radius, base.max(top) + *radius)
+            }
+            Solid::Mesh(triangles) => triangles.iter().flatten().fold(
+                (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+                |(min, max), &v| (min.min(v), max.max(v)),
+            ),
+        }
+    }
+
+    fn contains(&self, p: Vec3) -> bool {
+        match self {
+            Solid::Box(min, max) => p.cmpge(*min).all() && p.cmplt(*max).all(),
+            Solid::Sphere(center, radius) => p.distance_squared(*center) < radius * radius,
+            Solid::Cylinder(base, axis, radius) => {
+                let t = (p - *base).dot(*axis) / axis.length_squared();
+                let closest = *base + *axis * t;
+                (0. ..1.).contains(&t) && p.distance_squared(closest) < radius * radius
+            }
+            Solid::Mesh(triangles) => inside_mesh(triangles, p),
+        }
+    }
+}
+
+// Inside if a ray out of it crosses the surface an odd number of times
+// The direction is skewed so it doesn't run along the edges of axis aligned meshes
+pub(crate) fn inside_mesh(triangles: &[[Vec3; 3]], p: Vec3) -> bool {
+    let dir = Vec3::new(1., 0.000123, 0.000457).normalize();
+    triangles.iter().filter(|tri| ray_hits_triangle(p, dir, tri)).count() % 2 == 1
+}
+
+// Möller–Trumbore, only hits in front of the origin count
+fn ray_hits_triangle(origin: Vec3, dir: Vec3, [a, b, c]: &[Vec3; 3]) -> bool {
+    let e1 = *b - *a;
+    let e2 = *c - *a;
+    let h = dir.cross(e2);
+    let det = e1.dot(h);
+    if det.abs() < 1e-9 {
+        return false;
+    }
+    let s = origin - *a;
+    let u = s.dot(h) / det;
+    if !(0. ..=1.).contains(&u) {
+        return false;
+    }
+    let q = s.cross(e1);
+    let v = dir.dot(q) / det;
+    if v < 0. || u + v > 1. {
+        return false;
+    }
+    e2.dot(q) / det > 0.
+}
+
+// Just enough OBJ to get triangles out, v and f lines, polygons get fanned into triangles
+fn load_obj(path: &Path) -> anyhow::Result<Vec<[Vec3; 3]>> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("couldn't read mesh {}: {e}", path.display()))?;
+    let mut vertices = vec![];
+    let mut triangles = vec![];
+    for (n, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("v") => {
+                let coords: Vec<f32> = words.take(3).map(str::parse).collect::<Result<_, _>>()
+                    .map_err(|e| anyhow::anyhow!("{}:{}: {e}", path.display(), n + 1))?;
+                anyhow::ensure!(coords.len() == 3, "{}:{}: vertex needs 3 coordinates", path.display(), n + 1);
+                vertices.push(Vec3::new(coords[0], coords[1], coords[2]));
+            }
+            Some("f") => {
+                // Faces look like 1, 1/2 or 1/2/3, only the position index matters and
+                // negative ones count back from the end
+                let face = words.map(|w| {
+                    let i: i64 = w.split('/').next().unwrap_or("").parse()?;
+                    let i = if i < 0 {vertices.len() as i64 + i} else {i - 1};
+                    vertices.get(i as usize).copied()
+                        .ok_or_else(|| anyhow::anyhow!("vertex {} doesn't exist", i + 1))
+                }).collect::<anyhow::Result<Vec<Vec3>>>()
+                    .map_err(|e| anyhow::anyhow!("{}:{}: {e}", path.display(), n + 1))?;
+                for i in 1..face.len().saturating_sub(1) {
+                    triangles.push([face[0], face[i], face[i + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+    anyhow::ensure!(!triangles.is_empty(), "mesh {} has no faces", path.display());
+    Ok(triangles)
+}
+
+/// Replaces `World`, `Colliders`, and `SimParams` if the scene has them, with the scene in
+/// `SceneFile`
+pub fn load_scene(
+    file: Option<Res<SceneFile>>,
+    mut world: ResMut<World>,
+    mut params: ResMut<SimParams>,
+    mut colliders: ResMut<Colliders>,
+    materials: Res<Materials>,
+) {
+    let Some(file) = file else {
+        return;
+    };
+    let loaded = MpmScene::load(&file.0).and_then(|scene| Ok((scene.build(&materials)?, scene.colliders()?, scene.params)));
+    match loaded {
+        Ok((new_world, new_colliders, new_params)) => {
+            *world = new_world;
+            *colliders = new_colliders;
+            if let Some(new_params) = new_params {
+                *params = new_params;
+            }
+        }
+        Err(e) => error!("Couldn't load scene {}: {e}", file.0.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{prelude::*, math::Vec3A};
+    use crate::{boundary::Wall, collider::Boundary, material::Materials, particle::Particle};
+    use super::MpmScene;
+
+    // Own directory for every test and test run, so parallel runs don't write over each other
+    fn test_dir(test: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("ampm_{}_{test}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn fills_blocks() {
+        let scene = MpmScene::from_ron(r#"(
+            domain: Some((min: (0, 0, 0), max: (48, 48, 48))),
+            params: Some((gravity: -1.0)),
+            blocks: [
+                (shape: Box(min: (8.0, 8.0, 8.0), max: (16.0, 16.0, 16.0))),
+                (
+                    shape: Sphere(center: (32.0, 32.0, 32.0), radius: 6.0),
+                    velocity: (0.0, -2.0, 0.0),
+                    mass: 2.0,
+                    spacing: 0.5,
+                ),
+            ],
+        )"#).unwrap();
+        assert_eq!(scene.params.as_ref().unwrap().gravity, -1.);
+        let world = scene.build(&Materials::default()).unwrap();
+        assert_eq!(world.bounds, Some((IVec3::ZERO, IVec3::splat(48))));
+
+        let particles = world.particles();
+        let (cube, ball): (Vec<&Particle>, Vec<&Particle>) = particles.iter().partition(|p| p.x.x < 24.);
+        assert_eq!(cube.len(), 512);
+        assert!(cube.iter().all(|p| p.m == 1. && p.v == Vec3A::ZERO));
+        // 8 particles per unit of volume
+        let expected = 8. * 4. / 3. * std::f32::consts::PI * 6_f32.powi(3);
+        assert!((ball.len() as f32 - expected).abs() / expected < 0.05, "{} particles", ball.len());
+        assert!(ball.iter().all(|p| p.m == 2. && p.v.y == -2.));
+    }
+
+    #[test]
+    fn cylinder_along_any_axis() {
+        let scene = MpmScene::from_ron(r#"(
+            blocks: [(shape: Cylinder(base: (0.0, 0.0, 0.0), axis: (0.0, 0.0, -10.0), radius: 3.0), spacing: 0.5)],
+        )"#).unwrap();
+        let particles = scene.build(&Materials::default()).unwrap().particles();
+        assert!(particles.iter().all(|p| p.x.z < 0. && p.x.z > -10. && p.x.truncate().length() < 3.));
+        let expected = 8. * std::f32::consts::PI * 9. * 10.;
+        assert!((particles.len() as f32 - expected).abs() / expected < 0.1, "{} particles", particles.len());
+    }
+
+    #[test]
+    fn fills_meshes() {
+        let dir = test_dir("fills_meshes");
+        // Unit cube as quads
+        std::fs::write(dir.join("cube.obj"), "
+            v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\nv 1 0 1\nv 1 1 1\nv 0 1 1
+            f 1 4 3 2\nf 5 6 7 8\nf 1 2 6 5\nf 4 8 7 3\nf 1 5 8 4\nf 2 3 7 6
+        ").unwrap();
+        std::fs::write(dir.join("scene.ron"), r#"(
+            blocks: [(shape: Mesh(path: "cube.obj", scale: 4.0, offset: (-8.0, 0.0, 0.0)))],
+        )"#).unwrap();
+
+        let particles = MpmScene::load(dir.join("scene.ron")).unwrap().build(&Materials::default()).unwrap().particles();
+        assert_eq!(particles.len(), 64);
+        assert!(particles.iter().all(|p| p.x.x > -8. && p.x.x < -4.));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn loads_colliders() {
+        let dir = test_dir("loads_colliders");
+        std::fs::write(dir.join("tetra.obj"), "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 0 0 1\nf 1 3 2\nf 1 2 4\nf 1 4 3\nf 2 3 4").unwrap();
+        std::fs::write(dir.join("scene.ron"), r#"(
+            blocks: [],
+            colliders: [
+                (shape: Plane(normal: (0.0, 2.0, 0.0)), position: (0.0, 4.0, 0.0), boundary: Sticky),
+                (
+                    shape: Capsule(half_height: 2.0, radius: 1.0),
+                    position: (10.0, 10.0, 10.0),
+                    rotation: (0.0, 0.0, 1.5707964),
+                    velocity: (1.0, 0.0, 0.0),
+                    boundary: Separate(friction: 0.3),
+                ),
+                (shape: Mesh(path: "tetra.obj", scale: 6.0), position: (20.0, 0.0, 0.0), boundary: Slip),
+            ],
+        )"#).unwrap();
+
+        let colliders = MpmScene::load(dir.join("scene.ron")).unwrap().colliders().unwrap().0;
+        assert_eq!(colliders.len(), 3);
+        assert!((colliders[0].distance(Vec3A::new(3., 6., 3.)) - 2.).abs() < 1e-5);
+        // Lying along x after the quarter turn
+        assert!((colliders[1].distance(Vec3A::new(13.5, 10., 10.)) - 0.5).abs() < 1e-4);
+        assert_eq!(colliders[1].boundary, Boundary::Separate { friction: 0.3 });
+        assert!(colliders[2].distance(Vec3A::new(21., 1., 1.)) < 0.);
+        assert!(colliders[2].distance(Vec3A::new(25., 5., 5.)) > 0.);
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert!(MpmScene::from_ron(r#"(blocks: [], colliders: [(shape: Plane(normal: (0.0, 0.0, 0.0)), position: (0.0, 0.0, 0.0), boundary: Slip)])"#)
+            .and_then(|s| s.colliders()).is_err());
+    }
+
+    #[test]
+    fn domain_faces() {
+        let scene = MpmScene::from_ron(r#"(
+            domain: Some((
+                min: (0, 0, 0),
+                max: (24, 24, 24),
+                boundary: (min: (Periodic, Separate(friction: 0.5), Periodic), max: (Periodic, Outflow, Periodic)),
+            )),
+            blocks: [],
+        )"#).unwrap();
+        let world = scene.build(&Materials::default()).unwrap();
+        assert_eq!(world.boundary.min, [Wall::Periodic, Wall::Separate { friction: 0.5 }, Wall::Periodic]);
+        assert_eq!(world.boundary.max[1], Wall::Outflow);
+
+        // Left out it's slip everywhere, periodic on one side only doesn't work
+        let scene = MpmScene::from_ron("(domain: Some((min: (0, 0, 0), max: (24, 24, 24))), blocks: [])").unwrap();
+        assert_eq!(scene.build(&Materials::default()).unwrap().boundary.max, [Wall::Slip; 3]);
+        let scene = MpmScene::from_ron("(domain: Some((min: (0, 0, 0), max: (24, 24, 24), boundary: (min: (Periodic, Slip, Slip)))), blocks: [])").unwrap();
+        assert!(scene.build(&Materials::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_blocks() {
+        let bad = [
+            r#"(blocks: [(shape: Box(min: (0.0, 0.0, 0.0), max: (1.0, 1.0, 1.0)), mass: 0.0)])"#,
+            r#"(blocks: [(shape: Box(min: (0.0, 0.0, 0.0), max: (1.0, 1.0, 1.0)), spacing: -1.0)])"#,
+            r#"(blocks: [(shape: Box(min: (0.0, 0.0, 0.0), max: (1.0, 1.0, 1.0)), material: "plasma")])"#,
+            r#"(blocks: [(shape: Mesh(path: "not_a_file.obj"))])"#,
+        ];
+        for text in bad {
+            assert!(MpmScene::from_ron(text).and_then(|s| s.build(&Materials::default())).is_err(), "{text}");
+        }
+        assert!(MpmScene::from_ron("(blocks: [(shape: Cone())])").is_err());
+        assert!(MpmScene::from_ron("(blocks: [], params: Some((rest_density: 0.0)))").is_err());
+    }
+}
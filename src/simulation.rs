@@ -0,0 +1,69 @@
+use bevy::prelude::*;
+use crate::{MpmPlugin, world::World};
+
+/// Runs the solver inside an `App` built from `MinimalPlugins`, so no window or GPU is needed
+pub struct Simulation {
+    pub app: App,
+    frames: usize,
+}
+
+impl Simulation {
+    pub fn new() -> Self {
+        Simulation::from_world(World::new())
+    }
+
+    pub fn from_world(world: World) -> Self {
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, MpmPlugin))
+            .insert_resource(world);
+        Simulation { app, frames: 0 }
+    }
+
+    /// Advances the solver by one frame, `SimParams::frame_time` of simulation time in as many
+    /// steps as the CFL condition needs. Startup systems run on the first call
+    pub fn step(&mut self) {
+        self.app.update();
+        self.frames += 1;
+    }
+
+    pub fn run(&mut self, frames: usize) {
+        for _ in 0..frames {
+            self.step();
+        }
+    }
+
+    /// Number of frames advanced so far, `World::steps` counts the steps inside them
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn world(&self) -> &World {
+        self.app.world.resource::<World>()
+    }
+
+    pub fn world_mut(&mut self) -> Mut<'_, World> {
+        self.app.world.resource_mut::<World>()
+    }
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Simulation::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Vec3A;
+    use crate::{material::MaterialId, particle::Particle};
+    use super::Simulation;
+
+    #[test]
+    fn steps_without_window() {
+        let mut sim = Simulation::new();
+        sim.world_mut().add_particle(Particle::new(Vec3A::splat(4.), 1., MaterialId::water));
+        sim.run(3);
+        assert_eq!(sim.frames(), 3);
+        assert_eq!(sim.world().particles().len(), 1);
+    }
+}
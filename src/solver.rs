@@ -0,0 +1,381 @@
+use bevy::{prelude::*, math::{Vec3A, Mat3A}};
+use rayon::prelude::*;
+use crate::{collider::Colliders, material::Materials, morton, params::SimParams, rigid::RigidBodies, timestep::TimeStep, world::{Activity, Chunk, Halo, World}};
+// Picks the chunk levels at the start of every step and what each chunk does this substep
+pub fn schedule_substep(
+    mut world: ResMut<World>,
+    time_step: Res<TimeStep>,
+    params: Res<SimParams>,
+    materials: Res<Materials>,
+) {
+    if world.tick == 0 {
+        world.pick_levels(time_step.dt, params.cfl, &materials.wave_speeds(&params), &materials.max_dts(&params));
+    }
+    world.schedule_tick(time_step.dt);
+}
+
+pub fn clear_grid(
+    world: ResMut<World>
+) {
+    world.chunks.par_iter().for_each(|(_, c)| {
+        let mut chunk = c.lock().unwrap();
+        // Nodes nobody splats into this substep can stay stale
+        if chunk.activity < Activity::Grid {
+            return;
+        }
+        for node in chunk.nodes.iter_mut() {
+            node.zero(); 
+        }
+    });
+}
+
+// Quadratic b-spline weights of the 3x3x3 stencil around the particle's base node
+pub(crate) fn stencil_weights(x: Vec3A) -> (Vec3A, [Vec3A; 3]) {
+    // Original node coord
+    let ogn_coord = x.floor(); 
+    let ogn_diff = (x - ogn_coord) - 0.5;
+    let weights = [
+        0.5 * (0.5 - ogn_diff).powf(2.),
+        0.75 - (ogn_diff).powf(2.),
+        0.5 * (0.5 + ogn_diff).powf(2.),
+    ];
+    (ogn_coord, weights)
+}
+
+// Halo indices of the 3 nodes of a particle's stencil along each axis, the node at (gx, gy, gz) is
+// at stencil[0][gx] + stencil[1][gy] + stencil[2][gz]
+// The stencil's nodes along each axis are found by stepping the packed id of its lowest node along
+// that axis with morton::add
+fn halo_stencil(ogn_coord: Vec3A) -> [[usize; 3]; 3] {
+    let base = ogn_coord.as_uvec3();
+    let mut stencil = [[0; 3]; 3];
+    for (axis, mask) in [morton::BLOCK_MX, morton::BLOCK_MY, morton::BLOCK_MZ].into_iter().enumerate() {
+        // The stencil starts a node below ogn_coord, the halo is indexed from a node below the chunk
+        let mut id = Halo::axis_ids[axis][base[axis] as usize];
+        // The lowest bit of an axis is 1 along it
+        let one = mask & mask.wrapping_neg();
+        for slot in &mut stencil[axis] {
+            *slot = Halo::slot(id);
+            id = morton::add(id, one);
+        }
+    }
+    stencil
+}
+
+pub fn p2g1 (
+    world: ResMut<World>,
+) {
+    splat_momentum(&world);
+}
+
+// Splats the mass and momentum of every particle that splats this substep onto the grid,
+// through the halos so no chunk has to wait on another
+pub fn splat_momentum(world: &World) {
+    let halos = world.scatter(|_, chunk, halo| {
+        for p in &chunk.particles {
+            let (ogn_coord, weights) = stencil_weights(p.x);
+            let stencil = halo_stencil(ogn_coord);
+
+            for gx in 0..3 {
+                for gy in 0..3 {
+                    for gz in 0..3 {
+                        let weight = weights[gx].x * weights[gy].y * weights[gz].z;
+
+                        let rn_coord = ogn_coord + Vec3A::new(gx as f32 - 1., gy as f32 - 1., gz as f32 - 1.);  
+                        let node_dist = (rn_coord - p.x) + 0.5;
+
+                        let Q = p.C * node_dist;
+
+                        let m_contrib = weight * p.m;
+
+                        let node = &mut halo.nodes[stencil[0][gx] + stencil[1][gy] + stencil[2][gz]];
+                        node.m += m_contrib;
+                        node.v += m_contrib * (p.v + Q);
+                    }
+                }
+            }
+        }
+    });
+    // Nowhere to put mass outside the loaded chunks so it's dropped
+    world.merge_halos(&halos, |node, splat| {
+        node.m += splat.m;
+        node.v += splat.v;
+    });
+}
+
+pub fn p2g2 (
+    world: ResMut<World>,
+    params: Res<SimParams>,
+    materials: Res<Materials>,
+    ) {
+    // The grid mass around every splatting chunk
+    let masses = world.gather_halos(Activity::Splat);
+
+    let halos = world.scatter(|i, chunk, halo| {
+        let masses = &masses[&i];
+        for p in chunk.particles.iter_mut() {
+            let (ogn_coord, weights) = stencil_weights(p.x);
+            let stencil = halo_stencil(ogn_coord);
+
+            let mut density: f32 = 0.;
+            for gx in 0..3 {
+                for gy in 0..3 {
+                    for gz in 0..3 {
+                        let weight = weights[gx].x * weights[gy].y * weights[gz].z;
+                        density += masses.nodes[stencil[0][gx] + stencil[1][gy] + stencil[2][gz]].m * weight;
+                    }
+                }
+            }
+            // A particle's rest volume is its share of the grid mass the first time it's splatted
+            if p.V0 <= 0. {
+                p.V0 = p.m / density;
+            }
+            let model = materials.model(p.material);
+            let volume = model.volume(p, density);
+            let stress = model.stress(p, density, &params);
+
+            // The force goes on the grid, update_grid turns it into momentum with the dt of
+            // whoever reads the node
+            let eq_16_term_0 = -volume * 4. * stress;
+            for gx in 0..3 {
+                for gy in 0..3 {
+                    for gz in 0..3 {
+                        let weight = weights[gx].x * weights[gy].y * weights[gz].z;
+                        let rn_coord = ogn_coord + Vec3A::new(gx as f32 - 1., gy as f32 - 1., gz as f32 - 1.);  
+
+                        let cell_dist = (rn_coord - p.x) + 0.5;
+                        let force = eq_16_term_0 * weight * cell_dist;
+
+                        halo.nodes[stencil[0][gx] + stencil[1][gy] + stencil[2][gz]].f += force;
+                    }
+                }
+            }
+        }
+    });
+    world.merge_halos(&halos, |node, splat| node.f += splat.f);
+}
+
+pub fn update_grid (
+    world: ResMut<World>,
+    params: Res<SimParams>,
+    colliders: Res<Colliders>,
+    bodies: Res<RigidBodies>,
+) {
+    let bounds = world.bounds;
+    let boundary = world.boundary;
+    // Every chunk that got splatted into gets updated, halo chunks hold mass from their
+    // neighbours' particles too
+    // Nothing outside the chunk is touched so they can all go at once
+    world.chunks.par_iter().for_each(|(_, c)| {
+        let mut chunk = c.lock().unwrap();
+        if chunk.activity < Activity::Grid {
+            return;
+        }
+        let chunk_pos = chunk.pos;
+        let grid_dt = chunk.grid_dt;
+        // Momentum the nodes lose to each rigid body, they get it after the step
+        let mut impulses = vec![(Vec3A::ZERO, Vec3A::ZERO); bodies.len()];
+
+        for (i, node) in chunk.nodes.iter_mut().enumerate() {
+            // Empty nodes have no velocity, dividing would make them NaN
+            if node.m <= 0. {
+                continue;
+            }
+            node.v = (node.v + grid_dt * node.f) / node.m;
+            node.v.y += grid_dt * params.gravity; 
+
+            // Nodes sit in the middle of their cells
+            let pos = chunk_pos + Chunk::node_position(i);
+            node.v = colliders.collide(pos.as_vec3a() + 0.5, node.v);
+            node.v = bodies.collide(pos.as_vec3a() + 0.5, node.m, node.v, &mut impulses);
+
+            if let Some(bounds) = bounds {
+                node.v = boundary.collide(bounds, pos, node.v);
+            }
+        }
+        if !bodies.is_empty() {
+            bodies.add_impulses(&impulses);
+        }
+    });
+}
+
+pub fn g2p (
+    world: ResMut<World>,
+    time_step: Res<TimeStep>,
+    materials: Res<Materials>,
+    colliders: Res<Colliders>,
+    bodies: Res<RigidBodies>,
+) {
+    // The grid velocity around every chunk that splatted this substep, with it copied out they
+    // only need their own chunk so they can all go at once
+    let velocities = world.gather_halos(Activity::Splat);
+    velocities.par_iter().for_each(|(i, velocities)| {
+        let mut locked_chunk = world.chunks[i].lock().unwrap();
+        let chunk_pos = locked_chunk.pos.as_vec3a();
+        let chunk_dt = locked_chunk.dt(time_step.dt);
+        let due = locked_chunk.activity == Activity::Due;
+        let particles = &mut locked_chunk.particles;
+        // Loop through the particles, the new state is written straight back into the chunk
+        particles.iter_mut().for_each(|p| {
+            p.v = Vec3A::ZERO;
+
+            let (ogn_coord, weights) = stencil_weights(p.x);
+            let stencil = halo_stencil(ogn_coord);
+
+            let mut b: Mat3A = Mat3A::ZERO;
+            for gx in 0..3 {
+                for gy in 0..3 {
+                    for gz in 0..3 {
+                        let weight = weights[gx].x * weights[gy].y * weights[gz].z;
+                        let rn_coord = ogn_coord + Vec3A::new(gx as f32 - 1., gy as f32 - 1., gz as f32 - 1.);  
+
+                        let cell_dist = (rn_coord - p.x) + 0.5;
+                        // Nodes in chunks that aren't loaded have no velocity
+                        let w_v = velocities.nodes[stencil[0][gx] + stencil[1][gy] + stencil[2][gz]].v * weight;
+                        let term = Mat3A::from_cols(w_v * cell_dist.x, w_v * cell_dist.y, w_v * cell_dist.z);
+                        b += term;
+                        p.v += w_v;
+                    }
+                }
+            }
+            p.C = b.mul_scalar(4.);
+            // Chunks that only splatted still pick up the grid's velocity so whatever a finer
+            // neighbour pushed them with isn't lost, but they only move and deform when due
+            if !due {
+                return;
+            }
+            p.x += p.v * chunk_dt;
+            // C is the velocity gradient so this is how much the particle got deformed this substep
+            p.F = (Mat3A::IDENTITY + chunk_dt * p.C) * p.F;
+            materials.model(p.material).project(p);
+            // The grid keeps particles out of colliders but anything that still slipped in,
+            // like off a fast kinematic one, gets put back on the surface
+            p.x += colliders.push_out(chunk_pos + p.x);
+            p.x += bodies.colliders.push_out(chunk_pos + p.x);
+
+            // Push particles heading into the solid domain walls back out
+            if let Some(bounds) = world.bounds {
+                p.v += world.boundary.push_back(bounds, chunk_pos + p.x, p.v);
+            }
+        });
+        // Whatever went out through an outflow face is gone
+        if let (true, Some(bounds)) = (due, world.bounds) {
+            particles.retain(|p| !world.boundary.outflows(bounds, chunk_pos + p.x));
+        }
+    });
+}
+
+pub fn redistribute(
+    mut world: ResMut<World>,
+    params: Res<SimParams>,
+) {
+    world.redistribute();
+    // Every chunk is at the same time again at the end of the step
+    world.tick = (world.tick + 1) % World::substeps;
+    if world.tick == 0 {
+        world.prune();
+        world.steps += 1;
+        // checked_rem gives None for 0, which never sorts
+        if world.steps.checked_rem(params.sort_every as u64) == Some(0) {
+            world.sort_particles();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{math::Vec3A, prelude::{IVec3, UVec3}};
+    use crate::{MpmSchedule, material::{MaterialId, Materials}, params::SimParams, particle::Particle, simulation::Simulation, timestep::TimeStep, world::{Activity, Chunk, Halo, World}};
+    use super::splat_momentum;
+
+    #[test]
+    fn particle_falls_under_gravity() {
+        let start = Vec3A::new(12., 18., 12.);
+        let mut world = World::with_bounds(IVec3::ZERO, IVec3::splat(24));
+        world.add_particle(Particle::new(start, 1., MaterialId::water));
+        let mut sim = Simulation::from_world(world);
+        sim.run(10);
+        let p = sim.world().particles()[0];
+        assert!(p.x.y < start.y - 1., "particle didn't fall: {:?}", p.x);
+        assert!(p.v.y < 0.);
+    }
+
+    // Particles in the corner of a chunk splat into the 7 chunks around the corner through
+    // their halo, and gathering a halo brings the nodes back
+    #[test]
+    fn halos_reach_across_chunks() {
+        let mut world = World::new();
+        for x in [0.1, 0.6, 1.3] {
+            world.add_particle(Particle::new(Vec3A::new(x, 0.2, 0.4), 1., MaterialId::water));
+        }
+        world.pick_levels(0.4, 0.5, &[], &[]);
+        world.schedule_tick(0.4);
+        splat_momentum(&world);
+
+        let mass_in = |i: IVec3| world.chunk(i).unwrap().lock().unwrap().nodes.iter().map(|n| n.m).sum::<f32>();
+        let total: f32 = world.chunks.keys().map(|&i| mass_in(World::chunk_coords(i))).sum();
+        assert!((total - 3.).abs() < 1e-5, "{total}");
+        assert!(mass_in(IVec3::NEG_ONE) > 0.);
+        assert!(mass_in(IVec3::new(1, 0, 0)) == 0.);
+
+        let halo = world.gather_halo(World::chunk_id(IVec3::ZERO));
+        let corner = world.chunk(IVec3::NEG_ONE).unwrap().lock().unwrap().nodes[Chunk::node_index(IVec3::splat(7))];
+        assert_eq!(halo.nodes[Halo::index(IVec3::NEG_ONE)].m, corner.m);
+        let halo_mass: f32 = halo.nodes.iter().map(|n| n.m).sum();
+        assert!((halo_mass - 3.).abs() < 1e-5);
+    }
+
+    // A heavy block of sand hits a light block of water with no gravity, the transfers weigh
+    // everything by mass so the total momentum doesn't change
+    #[test]
+    fn mixed_masses_keep_momentum() {
+        let sand = Materials::default().id("sand").unwrap();
+        let mut world = World::with_bounds(IVec3::ZERO, IVec3::splat(32));
+        world.fill_block(Vec3A::new(10.25, 14.25, 14.25), UVec3::splat(8), 0.5, |x| Particle { v: Vec3A::X, ..Particle::new(x, 2., sand) });
+        world.fill_block(Vec3A::new(15.25, 14.25, 14.25), UVec3::splat(8), 0.5, |x| Particle { v: -Vec3A::X, ..Particle::new(x, 0.5, MaterialId::water) });
+        let momentum = |world: &World| world.particles().iter().fold(Vec3A::ZERO, |acc, p| acc + p.m * p.v);
+        let before = momentum(&world);
+        let mut sim = Simulation::from_world(world);
+        sim.app.insert_resource(SimParams { gravity: 0., ..Default::default() });
+        sim.run(20);
+
+        let after = momentum(sim.world());
+        let scale = sim.world().particles().iter().map(|p| p.m).sum::<f32>();
+        assert!((after - before).length() < 1e-3 * scale, "momentum went from {before} to {after}");
+        // The heavy sand keeps going and pushes the water along
+        let water_v = sim.world().particles().iter().filter(|p| p.material == MaterialId::water).fold(0., |acc, p| acc + p.v.x);
+        assert!(water_v > 0., "water still heads left");
+    }
+
+    // A slow particle next to a fast one splats every substep but only moves and deforms on the
+    // substeps its own chunk is due
+    #[test]
+    fn neighbours_wait_until_due() {
+        let mut world = World::new();
+        world.add_particle(Particle { v: Vec3A::new(0.1, 0., 0.), ..Particle::new(Vec3A::new(12., 4., 4.), 1., MaterialId::water) });
+        world.add_particle(Particle { v: Vec3A::new(0., 0., 30.), ..Particle::new(Vec3A::new(4., 4., 4.), 1., MaterialId::water) });
+        let mut sim = Simulation::from_world(world);
+        // Short enough that the slow particle's own chunk could take the whole step at once
+        sim.app.world.resource_mut::<TimeStep>().dt = 0.1;
+        let slow = |sim: &Simulation| *sim.world().particles().iter().find(|p| p.x.x > 8.).unwrap();
+
+        let start = slow(&sim);
+        let mut moves = 0;
+        for _ in 0..World::substeps {
+            let before = slow(&sim);
+            sim.app.world.run_schedule(MpmSchedule);
+            let after = slow(&sim);
+            if sim.world().chunk(IVec3::X).unwrap().lock().unwrap().activity == Activity::Due {
+                moves += 1;
+            }
+            else {
+                assert_eq!(after.x, before.x);
+                assert_eq!(after.F, before.F);
+            }
+        }
+        assert!(moves > 0 && moves < World::substeps, "slow chunk was due {moves} times");
+        // Its own dt adds up to the step no matter how often it's due
+        let moved = slow(&sim).x.x - start.x.x;
+        assert!((moved - 0.01).abs() < 0.001, "moved {moved}");
+    }
+}
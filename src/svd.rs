@@ -0,0 +1,107 @@
+use bevy::math::{Vec3A, Mat3A};
+
+/// Splits m into U * diag(sigma) * V^T where U and V are rotations. The singular values are
+/// sorted biggest first and only the last one can be negative, that's where a reflection goes
+pub fn svd(m: Mat3A) -> (Mat3A, Vec3A, Mat3A) {
+    // The eigenvectors of m^T m are V and its eigenvalues are the squared singular values
+    let (mut v, eigenvalues) = symmetric_eigen(m.transpose() * m);
+    let mut order = [0, 1, 2];
+    order.sort_by(|&a, &b| eigenvalues[b].total_cmp(&eigenvalues[a]));
+    v = Mat3A::from_cols(v.col(order[0]), v.col(order[1]), v.col(order[2]));
+    if v.determinant() < 0. {
+        v.z_axis = -v.z_axis;
+    }
+    let sigma0 = eigenvalues[order[0]].max(0.).sqrt();
+    let sigma1 = eigenvalues[order[1]].max(0.).sqrt();
+
+    // Everything's squashed to a point, any rotations work
+    if sigma0 < 1e-12 {
+        return (Mat3A::IDENTITY, Vec3A::ZERO, v);
+    }
+    let u0 = m * v.x_axis / sigma0;
+    // Squashed flat, u1 can be anything at a right angle to u0
+    let u1 = if sigma1 > 1e-6 * sigma0 {
+        let u1 = m * v.y_axis / sigma1;
+        (u1 - u0 * u0.dot(u1)).normalize()
+    }
+    else {
+        u0.any_orthonormal_vector()
+    };
+    let u2 = u0.cross(u1);
+    let sigma2 = u2.dot(m * v.z_axis);
+    (Mat3A::from_cols(u0, u1, u2), Vec3A::new(sigma0, sigma1, sigma2), v)
+}
+
+// Jacobi rotations until the off diagonals are gone, gives the eigenvectors as columns
+fn symmetric_eigen(m: Mat3A) -> (Mat3A, Vec3A) {
+    let mut a = m.to_cols_array_2d();
+    let mut v = Mat3A::IDENTITY.to_cols_array_2d();
+    let scale = a.iter().flatten().fold(0_f32, |s, x| s.max(x.abs()));
+    for _ in 0..16 {
+        let off = a[0][1].abs() + a[0][2].abs() + a[1][2].abs();
+        if off <= 1e-9 * scale {
+            break;
+        }
+        for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() <= f32::MIN_POSITIVE {
+                continue;
+            }
+            // Angle that zeroes a[p][q]
+            let theta = (a[q][q] - a[p][p]) / (2. * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.).sqrt());
+            let c = 1. / (t * t + 1.).sqrt();
+            let s = t * c;
+            for row in a.iter_mut() {
+                let (akp, akq) = (row[p], row[q]);
+                row[p] = c * akp - s * akq;
+                row[q] = s * akp + c * akq;
+            }
+            let (ap, aq) = (a[p], a[q]);
+            a[p] = std::array::from_fn(|k| c * ap[k] - s * aq[k]);
+            a[q] = std::array::from_fn(|k| s * ap[k] + c * aq[k]);
+            for col in v.iter_mut() {
+                let (vp, vq) = (col[p], col[q]);
+                col[p] = c * vp - s * vq;
+                col[q] = s * vp + c * vq;
+            }
+        }
+    }
+    // v was built up as rows
+    (Mat3A::from_cols_array_2d(&v).transpose(), Vec3A::new(a[0][0], a[1][1], a[2][2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::{Vec3A, Mat3A};
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+    use super::svd;
+
+    fn check(m: Mat3A) {
+        let (u, sigma, v) = svd(m);
+        let back = u * Mat3A::from_diagonal(sigma.into()) * v.transpose();
+        assert!(back.abs_diff_eq(m, 1e-4 * (1. + sigma.x)), "{m} came back as {back}");
+        for r in [u, v] {
+            assert!((r * r.transpose()).abs_diff_eq(Mat3A::IDENTITY, 1e-4), "{r} isn't a rotation");
+            assert!((r.determinant() - 1.).abs() < 1e-4);
+        }
+        assert!(sigma.x >= sigma.y && sigma.y >= sigma.z.abs() - 1e-5, "{sigma}");
+    }
+
+    #[test]
+    fn svd_works() {
+        check(Mat3A::IDENTITY);
+        check(Mat3A::ZERO);
+        check(Mat3A::from_diagonal(bevy::math::Vec3::new(1., 2., 3.)));
+        // Reflections end up in the last singular value
+        let (_, sigma, _) = svd(Mat3A::from_diagonal(bevy::math::Vec3::new(1., -1., 1.)));
+        assert!((sigma - Vec3A::new(1., 1., -1.)).abs().max_element() < 1e-5);
+        // Flat and squashed to a line
+        check(Mat3A::from_cols(Vec3A::X, Vec3A::Y, Vec3A::ZERO));
+        check(Mat3A::from_cols(Vec3A::X, Vec3A::X, Vec3A::X));
+
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..1000 {
+            check(Mat3A::from_cols_array(&[(); 9].map(|_| rng.gen_range(-2. ..2.))));
+        }
+    }
+}
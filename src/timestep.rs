@@ -0,0 +1,80 @@
+use bevy::prelude::*;
+use crate::{params::SimParams, world::World};
+
+/// dt of the step currently being taken, picked every step from a CFL condition, chunks split it
+/// into substeps
+#[derive(Resource, Debug, Clone)]
+pub struct TimeStep {
+    pub dt: f32,
+}
+
+impl Default for TimeStep {
+    fn default() -> Self {
+        TimeStep { dt: SimParams::default().max_dt }
+    }
+}
+
+impl TimeStep {
+    /// Leftover frame time too small to bother stepping for
+    pub const epsilon: f32 = 1e-6;
+
+    /// Picks dt for the next step so that the fastest signal, `World::max_signal_speed`, moves
+    /// at most cfl nodes per substep at the finest level, and those substeps are no longer than
+    /// `World::stable_dt`.
+    /// Never goes past `remaining` so the frame ends exactly on `frame_time`
+    pub fn pick(&mut self, params: &SimParams, speed: f32, stable_dt: f32, remaining: f32) -> f32 {
+        let mut dt = params.max_dt;
+        // NaN velocities get the smallest step, not the biggest
+        if speed.is_nan() {
+            dt = params.min_dt;
+        }
+        else {
+            if speed > 0. {
+                dt = params.cfl * World::substeps as f32 / speed;
+            }
+            dt = dt.min(stable_dt * World::substeps as f32).clamp(params.min_dt, params.max_dt);
+        }
+        self.dt = dt.min(remaining);
+        self.dt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TimeStep;
+    use crate::{params::SimParams, world::World};
+
+    #[test]
+    fn dt_follows_cfl() {
+        let mut ts = TimeStep::default();
+        let params = SimParams::default();
+        // Nothing moving takes the largest step it can
+        assert_eq!(ts.pick(&params, 0., f32::INFINITY, 1.), params.max_dt);
+
+        let dt = ts.pick(&params, 20., f32::INFINITY, 1.);
+        assert!((20. * dt / World::substeps as f32 - params.cfl).abs() < 1e-6);
+
+        // Clamped on both ends
+        assert_eq!(ts.pick(&params, 1e9, f32::INFINITY, 1.), params.min_dt);
+        assert_eq!(ts.pick(&params, f32::NAN, f32::INFINITY, 1.), params.min_dt);
+
+        // Viscous enough and the finest substeps have to fit in stable_dt even when nothing moves
+        let dt = ts.pick(&params, 0., 0.025, 1.);
+        assert!((dt / World::substeps as f32 - 0.025).abs() < 1e-6);
+        assert_eq!(ts.pick(&params, 20., 0.025, 1.), ts.pick(&params, 20., f32::INFINITY, 1.).min(dt));
+    }
+
+    #[test]
+    fn steps_end_on_frame_time() {
+        let mut ts = TimeStep::default();
+        let params = SimParams { frame_time: 1., ..Default::default() };
+        let mut remaining = params.frame_time;
+        let mut steps = 0;
+        while remaining > TimeStep::epsilon {
+            remaining -= ts.pick(&params, 20., f32::INFINITY, remaining);
+            steps += 1;
+        }
+        assert_eq!(steps, 10);
+        assert!(remaining.abs() < 1e-5);
+    }
+}
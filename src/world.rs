@@ -1,42 +1,96 @@
-use bevy::{prelude::*, math::{Vec3A, Mat3A}};
+use bevy::{prelude::*, math::Vec3A};
 use std::sync::Mutex;
-use crate::particle::Particle;
+use crate::{boundary::DomainBoundary, morton, particle::Particle};
 use hashbrown::HashMap;
+use rayon::prelude::*;
 
 #[derive(Component, Debug, Clone, Copy,)]
 pub struct Node {
     // https://github.com/rust-lang/rust/issues/72353 
     pub v: Vec3A,    // velocity Z 
+    pub f: Vec3A,    // force from the particles' stress, applied in update_grid with the node's dt
     pub m: f32,     // mass
 }
 
 impl Node {
     pub fn new() -> Self {
-        return Node { v:Vec3A::ZERO, m: 0. }
+        Node { v:Vec3A::ZERO, f: Vec3A::ZERO, m: 0. }
     }
 
     pub fn zero(&mut self) {
         self.m = 0.;
         self.v = Vec3A::ZERO;
+        self.f = Vec3A::ZERO;
     }
 }
 
+impl Default for Node {
+    fn default() -> Self {
+        Node::new()
+    }
+}
+
+// What a chunk has to do in the current substep, each one includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Activity {
+    // Nothing near it is moving
+    Idle,
+    // Particles next to it splat into its nodes so they need clearing and updating
+    Grid,
+    // A neighbour is being advanced so its particles have to splat for the grid to be right
+    Splat,
+    // Its particles get advanced
+    Due,
+}
+
+// Sparse world, chunks only exist where there are particles plus a one chunk halo around them
 #[derive(Resource)]
-pub struct World{pub chunks: HashMap<IVec3, Mutex<Chunk>>}
+pub struct World{
+    // Keyed by the packed id of their lowest node, see World::chunk_id
+    pub chunks: HashMap<u64, Mutex<Chunk>>,
+    // Min and max node of the domain, what happens at its faces is up to boundary
+    // None lets particles go wherever they want
+    pub bounds: Option<(IVec3, IVec3)>,
+    pub boundary: DomainBoundary,
+    // Which substep of the current step we're on
+    pub tick: u32,
+    // Full steps taken so far
+    pub steps: u64,
+}
 
 pub struct Chunk{
     // lowest bottom left back corner
     pub pos: IVec3,
-    pub loopert: usize,
+    // True if the chunk has particles, false if it's only there as a halo
     pub update: bool,
+    // Steps since the chunk last had particles
+    pub idle: u32,
+    // The chunk steps with dt / 2^level, fast chunks get a higher level
+    pub level: u32,
+    pub activity: Activity,
+    // dt to update the nodes with this substep, that of the finest due chunk around it
+    pub grid_dt: f32,
+    // In packed morton order, see Chunk::node_index
     pub nodes: [Node; Chunk::num_nodes],
     pub particles: Vec<Particle>,
 }
 
 impl Chunk {
-    pub const loopert_width: usize = 3;
     pub const width: usize = 8;
     const num_nodes: usize = Chunk::width * Chunk::width * Chunk::width;
+    // The index bits of every coordinate along each axis, packed ids don't share bits between
+    // axes so a node's index is its three entries or'd together
+    pub const axis_index: [[usize; Chunk::width]; 3] = {
+        let mut table = [[0; Chunk::width]; 3];
+        let mut c = 0;
+        while c < Chunk::width as u32 {
+            table[0][c as usize] = morton::chunk_index(c, 0, 0);
+            table[1][c as usize] = morton::chunk_index(0, c, 0);
+            table[2][c as usize] = morton::chunk_index(0, 0, c);
+            c += 1;
+        }
+        table
+    };
 
     // If it's in the chunk it will be at 0,0,0 else it gives -1 or +1 depending on surrounding
     // chunks
@@ -57,9 +111,74 @@ impl Chunk {
         rc_coord
     }
 
+    pub fn new(i: IVec3, update: bool) -> Self {
+        Chunk {
+            pos: i * Chunk::width as i32,
+            nodes: [Node::new(); Chunk::num_nodes],
+            update,
+            idle: 0,
+            level: 0,
+            activity: Activity::Idle,
+            grid_dt: 0.,
+            particles: vec![],
+        }
+    }
+
+    // How long the chunk's substeps are for a full step of dt
+    pub fn dt(&self, dt: f32) -> f32 {
+        dt / (1 << self.level) as f32
+    }
+
+    // Moves a chunk local position into the chunk at offset, rounding can land it right on the
+    // far face of its new chunk, which belongs to the next one, so it's kept just inside
+    pub fn rebase(x: Vec3A, offset: IVec3) -> Vec3A {
+        let x = x - (offset * Chunk::width as i32).as_vec3a();
+        x.clamp(Vec3A::ZERO, Vec3A::splat(Chunk::width as f32 * (1. - f32::EPSILON)))
+    }
+
+    // Which chunk a chunk local position ended up in, relative to this one
+    pub fn chunk_offset(x: Vec3A) -> IVec3 {
+        (x / Chunk::width as f32).floor().as_ivec3()
+    }
+
+    // Where the node at a chunk local position is in nodes, each 4x4x4 block of nodes is
+    // contiguous so a particle's stencil mostly stays in one or two of them
+    pub fn node_index(pos: IVec3) -> usize {
+        Chunk::axis_index[0][pos.x as usize] | Chunk::axis_index[1][pos.y as usize] | Chunk::axis_index[2][pos.z as usize]
+    }
+
+    pub fn node_position(i: usize) -> IVec3 {
+        IVec3::from_array(morton::chunk_position(i).map(|c| c as i32))
+    }
+
+    // Puts the particles in the packed order of the node they're in, so particles that are next
+    // to each other in the list splat into the same nodes one after the other
+    // There are only num_nodes keys so it's a single pass of a radix sort
+    pub fn sort_particles(&mut self) {
+        // Nothing to copy the sorted ones over
+        if self.particles.is_empty() {
+            return;
+        }
+        let keys: Vec<usize> = self.particles.iter().map(|p| {
+            Chunk::node_index(p.x.floor().as_ivec3().clamp(IVec3::ZERO, IVec3::splat(Chunk::width as i32 - 1)))
+        }).collect();
+        let mut starts = [0; Chunk::num_nodes + 1];
+        for &key in &keys {
+            starts[key + 1] += 1;
+        }
+        for i in 0..Chunk::num_nodes {
+            starts[i + 1] += starts[i];
+        }
+        let mut sorted = vec![self.particles[0]; self.particles.len()];
+        for (&key, p) in keys.iter().zip(&self.particles) {
+            sorted[starts[key]] = *p;
+            starts[key] += 1;
+        }
+        self.particles = sorted;
+    }
+
     pub fn get_index(width: usize, x: i32, y: i32, z:i32) -> usize {
-        let index = (x as usize * width * width) + (y as usize * width) + z as usize;
-        return index
+        (x as usize * width * width) + (y as usize * width) + z as usize
     }
     pub fn pos_from_index(width: usize, i: usize) -> IVec3{
         let x = i / (width * width);
@@ -70,8 +189,12 @@ impl Chunk {
 }
 
 impl World {
-    const width: usize = 3;
-    const map_chunks: usize = World::width * World::width * World::width;
+    // Steps an empty chunk sticks around before it gets freed
+    pub const grace_steps: u32 = 32;
+    // Finest level a chunk can step at, a full step is 2^max_level substeps and a chunk at
+    // level l gets advanced in 2^l of them
+    pub const max_level: u32 = 2;
+    pub const substeps: u32 = 1 << World::max_level;
     const surrounding_chunk_offsets: [IVec3; 27] = [
         IVec3::new(-1, -1, -1),
         IVec3::new(-1, -1, 0),
@@ -101,83 +224,572 @@ impl World {
         IVec3::new(1, 1, 0),
         IVec3::new(1, 1, 1),
     ];
+    // The same offsets as packed ids, adding one onto a chunk's id gives the id of that neighbour
+    const surrounding_chunk_shifts: [u64; 27] = {
+        let mut shifts = [0; 27];
+        let mut k = 0;
+        while k < 27 {
+            shifts[k] = World::chunk_id(World::surrounding_chunk_offsets[k]);
+            k += 1;
+        }
+        shifts
+    };
 
     pub fn new() -> Self {
-        let mut world = World{chunks: HashMap::new()};
-        for x in 0..World::width {
-            for y in 0..World::width {
-                for z in 0..World::width {
-                    // real modulo, -1.rem_euclid(3) = 2
-                    // these are just the x y z mod 3 i decided to name them like this cause i'm
-                    // stupid
-                    let looxer = x.rem_euclid(Chunk::loopert_width);
-                    let looyer = y.rem_euclid(Chunk::loopert_width);
-                    let loozer = z.rem_euclid(Chunk::loopert_width);
-                    let mut edge = false;
-                    if (x % (World::width - 1) == 0) || (y % (World::width - 1) == 0) || (z % (World::width - 1) == 0) {
-                        edge = true;
-                    }
-                    let vec: Vec<Node> = (0..Chunk::num_nodes).into_iter().fold(vec![], |mut acc, _| {
-                        acc.push(Node{v: Vec3A::ZERO, m: 0.});
-                        acc
-                    });
-                    let chunk: [Node; Chunk::num_nodes] = vec.try_into().unwrap();
-                    world.chunks.insert(IVec3::new(x as i32, y as i32, z as i32), Mutex::new(
-                        Chunk{
-                        pos: IVec3::new((x * Chunk::width) as i32, (y * Chunk::width) as i32, (z * Chunk::width) as i32),
-                        nodes: chunk,
-                        update: !edge,
-                        loopert: (looxer * Chunk::loopert_width * Chunk::loopert_width) + (looyer * Chunk::loopert_width) + loozer,
-                        particles: vec![]
-                        }
-                    ));
+        World{chunks: HashMap::new(), bounds: None, boundary: DomainBoundary::default(), tick: 0, steps: 0}
+    }
+
+    // Domain with slip walls on every face
+    pub fn with_bounds(min: IVec3, max: IVec3) -> Self {
+        World{chunks: HashMap::new(), bounds: Some((min, max)), boundary: DomainBoundary::default(), tick: 0, steps: 0}
+    }
+
+    pub fn with_boundary(min: IVec3, max: IVec3, boundary: DomainBoundary) -> anyhow::Result<Self> {
+        boundary.validate(min, max)?;
+        Ok(World{chunks: HashMap::new(), bounds: Some((min, max)), boundary, tick: 0, steps: 0})
+    }
+
+    fn wrap(&self) -> Wrap {
+        let Some((min, max)) = self.bounds else {
+            return Wrap { periodic: [false; 3], min: IVec3::ZERO, max: IVec3::ZERO };
+        };
+        let periodic = [0, 1, 2].map(|axis| self.boundary.is_periodic(axis));
+        Wrap { periodic, min, max }
+    }
+
+    // Packed id of the lowest node of chunk i, chunks are 2x2x2 blocks so it's also the id of
+    // their first block
+    pub const fn chunk_id(i: IVec3) -> u64 {
+        let width = Chunk::width as i32;
+        morton::packi(i.x * width, i.y * width, i.z * width)
+    }
+
+    pub fn chunk_coords(id: u64) -> IVec3 {
+        IVec3::from_array(morton::unpacki(id)) / Chunk::width as i32
+    }
+
+    // The chunk at i if it's loaded
+    pub fn chunk(&self, i: IVec3) -> Option<&Mutex<Chunk>> {
+        self.chunks.get(&World::chunk_id(i))
+    }
+
+    // Id of the chunk next to chunk id in a direction, across periodic faces it's on the other side
+    pub fn neighbour(&self, id: u64, offset: IVec3) -> u64 {
+        self.wrap().id(morton::add(id, World::chunk_id(offset)))
+    }
+
+    // Adds a particle whose position is in world node coordinates
+    pub fn add_particle(&mut self, mut p: Particle) {
+        p.x = self.wrap().position(p.x);
+        let i = Chunk::chunk_offset(p.x);
+        p.x = Chunk::rebase(p.x, i);
+        let id = World::chunk_id(i);
+        self.activate_chunk(id);
+        self.chunks.get_mut(&id).unwrap().get_mut().unwrap().particles.push(p);
+    }
+
+    // Test fixture, count particles along each axis spacing apart from min, made by particle
+    // from their positions
+    #[cfg(test)]
+    pub(crate) fn fill_block(&mut self, min: Vec3A, count: UVec3, spacing: f32, particle: impl Fn(Vec3A) -> Particle) {
+        for x in 0..count.x {
+            for y in 0..count.y {
+                for z in 0..count.z {
+                    self.add_particle(particle(min + Vec3A::new(x as f32, y as f32, z as f32) * spacing));
                 }
             }
         }
-        world
     }
-    pub fn get_surrounding_chunks (&self, pos: IVec3) -> Vec<&Mutex<Chunk>> { 
-            // Gather surrounding chunks
-            let mut surrounding_chunks = vec![];
-            // Reads all the surrounding chunks and puts them in a list
-            for j in 0..World::surrounding_chunk_offsets.len(){
-                let coord = pos + World::surrounding_chunk_offsets[j]; 
-                let outside_chunk = self.chunks.get(&coord).unwrap();
-                surrounding_chunks.push(outside_chunk);
+
+    // Speed of the fastest particle anywhere
+    pub fn max_speed(&self) -> f32 {
+        self.chunks.par_iter().map(|(_, c)| {
+            let chunk = c.lock().unwrap();
+            chunk.particles.iter().fold(0_f32, |acc, p| acc.max(p.v.length()))
+        }).reduce(|| 0., f32::max)
+    }
+
+    /// Fastest anything can travel through the world, a particle's speed plus how fast waves go
+    /// through its material. wave_speeds is indexed by `MaterialId`
+    pub fn max_signal_speed(&self, wave_speeds: &[f32]) -> f32 {
+        self.chunks.par_iter().map(|(_, c)| {
+            let chunk = c.lock().unwrap();
+            World::chunk_signal_speed(&chunk, wave_speeds)
+        }).reduce(|| 0., f32::max)
+    }
+
+    fn chunk_signal_speed(chunk: &Chunk, wave_speeds: &[f32]) -> f32 {
+        chunk.particles.iter().fold(0_f32, |acc, p| {
+            acc.max(p.v.length() + wave_speeds.get(p.material.0 as usize).copied().unwrap_or(0.))
+        })
+    }
+
+    /// Longest substep every particle's material is stable for, see
+    /// `ConstitutiveModel::max_dt`. max_dts is indexed by `MaterialId`
+    pub fn stable_dt(&self, max_dts: &[f32]) -> f32 {
+        self.chunks.par_iter().map(|(_, c)| {
+            let chunk = c.lock().unwrap();
+            World::chunk_stable_dt(&chunk, max_dts)
+        }).reduce(|| f32::INFINITY, f32::min)
+    }
+
+    fn chunk_stable_dt(chunk: &Chunk, max_dts: &[f32]) -> f32 {
+        chunk.particles.iter().fold(f32::INFINITY, |acc, p| {
+            acc.min(max_dts.get(p.material.0 as usize).copied().unwrap_or(f32::INFINITY))
+        })
+    }
+
+    // Picks every chunk's level so that its fastest particle plus the wave speed of its material
+    // moves at most cfl nodes per substep, and the substep is no longer than its materials'
+    // max_dts allow
+    // Neighbouring levels are kept within 1 of each other so the grid between them stays close
+    // to in sync, and halo chunks step as fine as their finest neighbour
+    pub fn pick_levels(&mut self, dt: f32, cfl: f32, wave_speeds: &[f32], max_dts: &[f32]) {
+        let wrap = self.wrap();
+        let mut levels: HashMap<u64, u32> = self.chunks.par_iter().map(|(&i, c)| {
+            let chunk = c.lock().unwrap();
+            // How many substeps of each limit the step needs
+            let speed = World::chunk_signal_speed(&chunk, wave_speeds);
+            let needed = (speed * dt / cfl).max(dt / World::chunk_stable_dt(&chunk, max_dts));
+            let level = needed.log2().ceil().clamp(0., World::max_level as f32);
+            // NaN turns into 0 which is as good as anything
+            (i, level as u32)
+        }).collect();
+
+        for _ in 0..World::max_level {
+            levels = levels.par_iter().map(|(&i, &level)| {
+                let nbh_max = wrap.around(i)
+                    .filter_map(|(_, j)| levels.get(&j))
+                    .max()
+                    .copied()
+                    .unwrap_or(0);
+                (i, level.max(nbh_max.saturating_sub(1)))
+            }).collect();
+        }
+
+        for (&i, c) in self.chunks.iter_mut() {
+            let chunk = c.get_mut().unwrap();
+            chunk.level = if chunk.particles.is_empty() {
+                wrap.around(i)
+                    .filter_map(|(_, j)| levels.get(&j))
+                    .max()
+                    .copied()
+                    .unwrap_or(0)
+            }
+            else {
+                levels[&i]
+            };
+        }
+    }
+
+    // Works out what every chunk has to do in the current tick, a chunk at level l is due every
+    // 2^(max_level - l) ticks and everything around it has to help build its grid
+    pub fn schedule_tick(&mut self, dt: f32) {
+        let tick = self.tick;
+        let wrap = self.wrap();
+        let due: HashMap<u64, f32> = self.chunks.iter_mut().filter_map(|(&i, c)| {
+            let chunk = c.get_mut().unwrap();
+            let every = 1 << (World::max_level - chunk.level.min(World::max_level));
+            (tick.is_multiple_of(every) && !chunk.particles.is_empty()).then(|| (i, chunk.dt(dt)))
+        }).collect();
+
+        let activities: Vec<(u64, Activity, Option<f32>)> = self.chunks.keys().map(|&i| {
+            let mut activity = Activity::Idle;
+            let mut grid_dt: Option<f32> = None;
+            for (offset, j) in wrap.around(i) {
+                if let Some(&due_dt) = due.get(&j) {
+                    activity = activity.max(if offset == IVec3::ZERO {Activity::Due} else {Activity::Splat});
+                    grid_dt = Some(grid_dt.map_or(due_dt, |g| g.min(due_dt)));
+                }
+            }
+            (i, activity, grid_dt)
+        }).collect();
+
+        for &(i, activity, grid_dt) in &activities {
+            let chunk = self.chunks.get_mut(&i).unwrap().get_mut().unwrap();
+            chunk.activity = activity;
+            chunk.grid_dt = grid_dt.unwrap_or(chunk.dt(dt));
+        }
+        // Anything next to a splatting chunk gets splatted into
+        for &(i, activity, _) in &activities {
+            if activity < Activity::Splat {
+                continue;
+            }
+            for (_, j) in wrap.around(i) {
+                if let Some(c) = self.chunks.get_mut(&j) {
+                    let chunk = c.get_mut().unwrap();
+                    chunk.activity = chunk.activity.max(Activity::Grid);
+                }
             }
-            surrounding_chunks
+        }
     }
-}
-#[cfg(test)]
-mod tests {
-    use bevy::{math::Vec3A, prelude::IVec3};
-    use crate::world::Chunk;
-    use super::World;
 
-    #[test]
-    fn chunks_update_properly() {
-        let world = World::new();
-        world.chunks.iter().for_each(|(i, c)| {
+    // Sorts the particles of every chunk by the node they're in, see Chunk::sort_particles
+    pub fn sort_particles(&mut self) {
+        self.chunks.par_iter_mut().for_each(|(_, c)| {
+            c.get_mut().unwrap().sort_particles();
+        });
+    }
+
+    // Copies of every particle with their position in world node coordinates
+    pub fn particles(&self) -> Vec<Particle> {
+        let mut particles = vec![];
+        for c in self.chunks.values() {
             let chunk = c.lock().unwrap();
-            let mut edge = false;
-            if i.x == 0 || i.x == World::width as i32 - 1{
-                edge = true;
+            particles.extend(chunk.particles.iter().map(|p| {
+                Particle { x: p.x + chunk.pos.as_vec3a(), ..*p }
+            }));
+        }
+        particles
+    }
+
+    // Makes sure the chunk exists and is updated, along with all 26 chunks around it so that
+    // its particles always have somewhere to splat to
+    pub fn activate_chunk(&mut self, id: u64) {
+        let wrap = self.wrap();
+        for (_, j) in wrap.around(id) {
+            self.chunks.entry(j).or_insert_with(|| Mutex::new(Chunk::new(World::chunk_coords(j), false)));
+        }
+        self.chunks.get_mut(&id).unwrap().get_mut().unwrap().update = true;
+    }
+
+    // Moves every particle that left its chunk during g2p into the chunk it's in now,
+    // rebasing its position to be local to that chunk
+    pub fn redistribute(&mut self) {
+        let wrap = self.wrap();
+        let movers: Vec<(u64, u32, Particle)> = self.chunks.par_iter().flat_map_iter(|(&i, c)| {
+            let mut chunk = c.lock().unwrap();
+            let level = chunk.level;
+            let mut leaving = vec![];
+            chunk.particles.retain(|p| {
+                let offset = Chunk::chunk_offset(p.x);
+                if offset == IVec3::ZERO {
+                    return true;
+                }
+                let mut moved = *p;
+                moved.x = Chunk::rebase(p.x, offset);
+                leaving.push((wrap.id(morton::add(i, World::chunk_id(offset))), level, moved));
+                false
+            });
+            leaving
+        }).collect();
+
+        for (target, level, p) in movers {
+            self.activate_chunk(target);
+            let chunk = self.chunks.get_mut(&target).unwrap().get_mut().unwrap();
+            // A chunk that just got its first particles keeps stepping like the one they came
+            // from until the levels get picked again
+            if chunk.particles.is_empty() {
+                chunk.level = level;
             }
-            if i.y == 0 || i.y == World::width as i32 - 1 {
-                edge = true;
+            chunk.particles.push(p);
+        }
+    }
+
+    // Flags chunks that ran out of particles as halos and frees the ones that have been empty
+    // for longer than the grace period and aren't in the halo of a chunk with particles
+    pub fn prune(&mut self) {
+        let wrap = self.wrap();
+        let mut needed = hashbrown::HashSet::new();
+        for (&i, c) in self.chunks.iter_mut() {
+            let chunk = c.get_mut().unwrap();
+            if chunk.particles.is_empty() {
+                chunk.update = false;
+                chunk.idle += 1;
+                continue;
             }
-            if i.z == 0 || i.z == World::width as i32 - 1 {
-                edge = true;
+            chunk.update = true;
+            chunk.idle = 0;
+            needed.extend(wrap.around(i).map(|(_, j)| j));
+        }
+        self.chunks.retain(|i, c| {
+            needed.contains(i) || c.get_mut().unwrap().idle <= World::grace_steps
+        });
+    }
+
+    // Runs splat on every chunk that splats this substep, each one gets a halo of its own to
+    // splat into so they can all go at once
+    pub fn scatter(&self, splat: impl Fn(u64, &mut Chunk, &mut Halo) + Sync) -> HashMap<u64, Halo> {
+        self.chunks.par_iter().filter_map(|(&i, c)| {
+            let mut chunk = c.lock().unwrap();
+            if chunk.activity < Activity::Splat {
+                return None;
             }
-            if edge == true {
-                assert!(chunk.update == false)
+            let mut halo = Halo::new();
+            splat(i, &mut chunk, &mut halo);
+            Some((i, halo))
+        }).collect()
+    }
+
+    // Adds the halos onto the nodes of every chunk being updated, add gets a node and what one
+    // of the halos over it has for it
+    // Each chunk only locks itself, the halos are only read
+    pub fn merge_halos(&self, halos: &HashMap<u64, Halo>, add: impl Fn(&mut Node, &Node) + Sync) {
+        let wrap = self.wrap();
+        self.chunks.par_iter().for_each(|(&i, c)| {
+            let mut chunk = c.lock().unwrap();
+            if chunk.activity < Activity::Grid {
+                return;
             }
-            else {
-                assert!(chunk.update == true)
+            for (offset, j) in wrap.around(i) {
+                let Some(halo) = halos.get(&j) else { continue };
+                // The halo is offset from this chunk, the part of it over this chunk is the part
+                // at -offset from its own
+                for pos in Halo::overlap(-offset) {
+                    let local = pos + offset * Chunk::width as i32;
+                    add(&mut chunk.nodes[Chunk::node_index(local)], &halo.nodes[Halo::index(pos)]);
+                }
             }
         });
     }
 
+    // Halos of the grid around every chunk that's at least as active as activity, gathered
+    // before anything gets changed so nobody has to lock their neighbours while they work
+    pub fn gather_halos(&self, activity: Activity) -> HashMap<u64, Halo> {
+        self.chunks.par_iter().filter_map(|(&i, c)| {
+            // The lock has to go before gathering, which locks the chunk again
+            let active = c.lock().unwrap().activity >= activity;
+            active.then(|| (i, self.gather_halo(i)))
+        }).collect()
+    }
+
+    // Copies the nodes of chunk i and the ones next to them in the chunks around it into a halo,
+    // locking the chunks one at a time. Nodes in chunks that aren't loaded are left empty
+    pub fn gather_halo(&self, id: u64) -> Halo {
+        let mut halo = Halo::new();
+        for (offset, j) in self.wrap().around(id) {
+            let Some(c) = self.chunks.get(&j) else { continue };
+            let chunk = c.lock().unwrap();
+            for pos in Halo::overlap(offset) {
+                let local = pos - offset * Chunk::width as i32;
+                halo.nodes[Halo::index(pos)] = chunk.nodes[Chunk::node_index(local)];
+            }
+        }
+        halo
+    }
+
+    // Gathers the 3x3x3 chunks around chunk id, chunks that aren't loaded are left as None
+    pub fn get_surrounding_chunks (&self, id: u64) -> Neighborhood<'_> { 
+        let mut chunks = [None; 27];
+        for (offset, j) in self.wrap().around(id) {
+            chunks[Neighborhood::index(offset)] = self.chunks.get(&j);
+        }
+        Neighborhood { chunks }
+    }
+}
+
+// Periodic axes of the domain, anything past one face comes back in through the other
+#[derive(Clone, Copy)]
+struct Wrap {
+    periodic: [bool; 3],
+    min: IVec3,
+    max: IVec3,
+}
+
+impl Wrap {
+    fn chunk(self, mut i: IVec3) -> IVec3 {
+        let width = Chunk::width as i32;
+        for axis in 0..3 {
+            if self.periodic[axis] {
+                let first = self.min[axis] / width;
+                let count = (self.max[axis] - self.min[axis]) / width;
+                i[axis] = first + (i[axis] - first).rem_euclid(count);
+            }
+        }
+        i
+    }
+
+    // Same as chunk for a chunk id, which only needs unpacking if there's an axis to wrap
+    fn id(self, id: u64) -> u64 {
+        if !self.periodic.contains(&true) {
+            return id;
+        }
+        World::chunk_id(self.chunk(World::chunk_coords(id)))
+    }
+
+    // The ids of the 27 chunks around chunk id with their offsets from it
+    fn around(self, id: u64) -> impl Iterator<Item = (IVec3, u64)> {
+        World::surrounding_chunk_offsets.into_iter().zip(World::surrounding_chunk_shifts)
+            .map(move |(offset, shift)| (offset, self.id(morton::add(id, shift))))
+    }
+
+    fn position(self, mut x: Vec3A) -> Vec3A {
+        for axis in 0..3 {
+            if self.periodic[axis] {
+                let min = self.min[axis] as f32;
+                x[axis] = min + (x[axis] - min).rem_euclid((self.max[axis] - self.min[axis]) as f32);
+            }
+        }
+        x
+    }
+}
+
+// The 3x3x3 chunks around a chunk, looked up by their offset from it so nobody has to remember
+// that the center is index 13
+pub struct Neighborhood<'a> {
+    chunks: [Option<&'a Mutex<Chunk>>; 27],
+}
+
+impl<'a> Neighborhood<'a> {
+    pub fn index(offset: IVec3) -> usize {
+        debug_assert!(offset.abs().max_element() <= 1, "{offset} isn't a neighbour");
+        Chunk::get_index(3, offset.x + 1, offset.y + 1, offset.z + 1)
+    }
+
+    pub fn get(&self, offset: IVec3) -> Option<&'a Mutex<Chunk>> {
+        self.chunks[Neighborhood::index(offset)]
+    }
+
+    pub fn center(&self) -> Option<&'a Mutex<Chunk>> {
+        self.get(IVec3::ZERO)
+    }
+
+    // True if all 27 chunks are loaded
+    pub fn is_complete(&self) -> bool {
+        self.chunks.iter().all(|c| c.is_some())
+    }
+
+    // Every loaded chunk along with its offset
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, &'a Mutex<Chunk>)> + '_ {
+        World::surrounding_chunk_offsets.into_iter().filter_map(|offset| {
+            self.get(offset).map(|c| (offset, c))
+        })
+    }
+}
+
+// A chunk's nodes plus one more node all around them, which is everywhere its particles' stencils
+// can reach. Particles splat into their own chunk's halo so nobody writes to anyone else's nodes,
+// and the halos get added onto the grid afterwards
+// Like the chunk the nodes are in 4x4x4 blocks in packed order, the blocks themselves go one row
+// after the other so the halo only has room for the blocks it needs
+pub struct Halo {
+    pub nodes: Vec<Node>,
+}
+
+impl Halo {
+    const blocks: usize = (Chunk::width + 2).div_ceil(4);
+    // Where each block goes in the halo, looked up by the block part of its nodes' packed ids
+    const block_slots: [usize; 64] = {
+        let mut slots = [0; 64];
+        let mut b = 0;
+        while b < Halo::blocks * Halo::blocks * Halo::blocks {
+            let (x, y, z) = (b / (Halo::blocks * Halo::blocks), b / Halo::blocks % Halo::blocks, b % Halo::blocks);
+            slots[morton::packed_index(morton::pack(x as u32 * 4, y as u32 * 4, z as u32 * 4)) / 64] = b;
+            b += 1;
+        }
+        slots
+    };
+
+    pub fn new() -> Self {
+        Halo { nodes: vec![Node::new(); Halo::blocks * Halo::blocks * Halo::blocks * 64] }
+    }
+
+    // Packed ids of the nodes along each axis with the others at -1, the halo starts a node below
+    // the chunk so they're indexed with the position plus 1
+    pub const axis_ids: [[u64; Chunk::width + 2]; 3] = {
+        let mut table = [[0; Chunk::width + 2]; 3];
+        let mut h = 0;
+        while h < Chunk::width + 2 {
+            let c = h as u32;
+            table[0][h] = morton::pack(c, 0, 0);
+            table[1][h] = morton::pack(0, c, 0);
+            table[2][h] = morton::pack(0, 0, c);
+            h += 1;
+        }
+        table
+    };
+
+    // Packed id of a chunk local node position, which goes from -1 to Chunk::width on every axis.
+    // The axes' bits don't overlap so it's their ids put together
+    pub fn packed_id(pos: IVec3) -> u64 {
+        let h = (pos + 1).as_uvec3();
+        Halo::axis_ids[0][h.x as usize] | Halo::axis_ids[1][h.y as usize] | Halo::axis_ids[2][h.z as usize]
+    }
+
+    // Index of the node with a packed id. The blocks go in rows so it's also the sum of the
+    // indices of the id's axes on their own
+    pub fn slot(id: u64) -> usize {
+        let i = morton::packed_index(id);
+        Halo::block_slots[i / 64] * 64 + i % 64
+    }
+
+    pub fn index(pos: IVec3) -> usize {
+        Halo::slot(Halo::packed_id(pos))
+    }
+
+    // The positions of the halo that are in the chunk at offset from its own, in the halo's frame
+    fn overlap(offset: IVec3) -> impl Iterator<Item = IVec3> {
+        let width = Chunk::width as i32;
+        let range = move |o: i32| match o {
+            -1 => -1..0,
+            0 => 0..width,
+            _ => width..width + 1,
+        };
+        range(offset.x).flat_map(move |x| {
+            range(offset.y).flat_map(move |y| range(offset.z).map(move |z| IVec3::new(x, y, z)))
+        })
+    }
+}
+
+impl Default for Halo {
+    fn default() -> Self {
+        Halo::new()
+    }
+}
+
+impl Default for World {
+    fn default() -> Self {
+        World::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{math::Vec3A, prelude::IVec3};
+    use crate::{boundary::{DomainBoundary, Wall}, material::MaterialId, morton, particle::Particle, world::{Activity, Chunk, Halo}};
+    use super::World;
+
+    fn particle(x: Vec3A) -> Particle {
+        Particle::new(x, 1., MaterialId::water)
+    }
+
+    #[test]
+    fn chunks_allocated_around_particles() {
+        let mut world = World::new();
+        assert!(world.chunks.is_empty());
+        world.add_particle(particle(Vec3A::new(-20.5, -3., 5.)));
+
+        // Only the chunk with the particle and its halo exist
+        assert_eq!(world.chunks.len(), 27);
+        let i = IVec3::new(-3, -1, 0);
+        for offset in World::surrounding_chunk_offsets {
+            let chunk = world.chunk(i + offset).unwrap().lock().unwrap();
+            assert!(chunk.update == (offset == IVec3::ZERO));
+        }
+        let chunk = world.chunk(i).unwrap().lock().unwrap();
+        assert_eq!(chunk.pos, IVec3::new(-24, -8, 0));
+        assert_eq!(chunk.particles[0].x, Vec3A::new(3.5, 5., 5.));
+    }
+
+    #[test]
+    fn empty_chunks_freed_after_grace_period() {
+        let mut world = World::new();
+        world.add_particle(particle(Vec3A::new(4., 4., 4.)));
+        world.chunks.get_mut(&World::chunk_id(IVec3::ZERO)).unwrap().get_mut().unwrap().particles[0].x.x = 12.;
+        world.redistribute();
+        world.prune();
+        assert_eq!(world.chunks.len(), 36);
+
+        for _ in 1..World::grace_steps {
+            world.prune();
+        }
+        assert_eq!(world.chunks.len(), 36);
+        world.prune();
+        // The chunks only the old position needed are gone
+        assert_eq!(world.chunks.len(), 27);
+        assert!(world.chunk(IVec3::new(-1, 0, 0)).is_none());
+    }
+
     #[test]
     fn in_bounds_works() {
         let mut numbers = vec![];
@@ -227,5 +839,220 @@ mod tests {
         let pos2 = Chunk::pos_from_index(43, index);
         assert!(pos == pos2);
     }
-    // Write two more tests, 1 for get_surrounding chunks and 1 for making sure loopert works
+
+    #[test]
+    fn nodes_are_in_morton_order() {
+        for i in 0..Chunk::width * Chunk::width * Chunk::width {
+            assert_eq!(Chunk::node_index(Chunk::node_position(i)), i);
+        }
+        assert_eq!(Chunk::node_index(IVec3::new(3, 3, 3)), 63);
+        assert_eq!(Chunk::node_index(IVec3::new(4, 0, 0)), 64);
+        assert_eq!(Chunk::node_position(511), IVec3::splat(7));
+    }
+
+    #[test]
+    fn chunk_ids_are_packed() {
+        for i in [IVec3::ZERO, IVec3::new(-3, 1, 7), IVec3::new(-1000, 20, -1)] {
+            let id = World::chunk_id(i);
+            assert_eq!(World::chunk_coords(id), i);
+            // Chunks are 2x2x2 blocks, their first block starts on their lowest node
+            assert_eq!(crate::morton::region_id(id), id);
+            for (offset, shift) in World::surrounding_chunk_offsets.into_iter().zip(World::surrounding_chunk_shifts) {
+                assert_eq!(crate::morton::add(id, shift), World::chunk_id(i + offset));
+            }
+        }
+    }
+
+    #[test]
+    fn halo_nodes_are_in_packed_blocks() {
+        let len = Halo::new().nodes.len();
+        let mut seen = vec![false; len];
+        let range = -1..=Chunk::width as i32;
+        for pos in range.clone().flat_map(|x| range.clone().flat_map(move |y| (-1..=Chunk::width as i32).map(move |z| IVec3::new(x, y, z)))) {
+            let i = Halo::index(pos);
+            assert!(i < len && !seen[i], "{pos} at {i}");
+            seen[i] = true;
+        }
+        // A block of the halo is the same as the chunk's, one node off
+        for i in 0..64 {
+            let pos = Chunk::node_position(i) - 1;
+            assert_eq!(Halo::index(pos), i);
+        }
+        assert_eq!(Halo::index(IVec3::new(3, -1, -1)), 9 * 64);
+        // Packed ids step along an axis with morton::add and split up into one index per axis,
+        // which is how stencils walk the halo
+        for pos in range.clone().flat_map(|x| range.clone().map(move |y| IVec3::new(x, y, 7 - x))) {
+            assert_eq!(Halo::packed_id(pos), morton::pack((pos.x + 1) as u32, (pos.y + 1) as u32, (pos.z + 1) as u32));
+            if pos.x > -1 {
+                let below = Halo::packed_id(pos - IVec3::X) & morton::BLOCK_MX;
+                assert_eq!(morton::add(below, Halo::axis_ids[0][1]), Halo::packed_id(pos) & morton::BLOCK_MX);
+            }
+            let on_axes = [morton::BLOCK_MX, morton::BLOCK_MY, morton::BLOCK_MZ].map(|mask| Halo::slot(Halo::packed_id(pos) & mask));
+            assert_eq!(Halo::index(pos), on_axes.iter().sum::<usize>());
+        }
+    }
+
+    #[test]
+    fn particles_sort_by_node() {
+        let mut world = World::new();
+        for x in [6.5, 1.2, 5.9, 1.7, 0.3, 3.1] {
+            world.add_particle(Particle { m: x, ..particle(Vec3A::new(x, x, 1.)) });
+        }
+        world.sort_particles();
+        let chunk = world.chunk(IVec3::ZERO).unwrap().lock().unwrap();
+        let order: Vec<f32> = chunk.particles.iter().map(|p| p.m).collect();
+        // Everything in the first 4x4x4 block comes first, and the sort keeps the order of
+        // particles in the same node
+        assert_eq!(order, [0.3, 1.2, 1.7, 3.1, 5.9, 6.5]);
+    }
+
+    // Every particle comes with empty halo chunks around it, those have nothing to sort
+    #[test]
+    fn sorting_skips_empty_chunks() {
+        let mut world = World::new();
+        world.add_particle(particle(Vec3A::new(4., 4., 4.)));
+        assert_eq!(world.chunks.values().filter(|c| c.lock().unwrap().particles.is_empty()).count(), 26);
+        world.sort_particles();
+        assert_eq!(world.particles().len(), 1);
+
+        let mut empty = Chunk::new(IVec3::ONE, false);
+        empty.sort_particles();
+        assert!(empty.particles.is_empty());
+    }
+
+    #[test]
+    fn particles_migrate_between_chunks() {
+        let mut world = World::new();
+        world.add_particle(particle(Vec3A::new(4., 4., 4.)));
+        world.chunks.get_mut(&World::chunk_id(IVec3::ZERO)).unwrap().get_mut().unwrap().particles[0].x = Vec3A::new(-0.5, 4., 9.);
+        world.redistribute();
+
+        let count = world.chunk(IVec3::ZERO).unwrap().lock().unwrap().particles.len();
+        assert_eq!(count, 0);
+        let target = world.chunk(IVec3::new(-1, 0, 1)).unwrap().lock().unwrap();
+        assert!(target.update);
+        assert_eq!(target.particles[0].x, Vec3A::new(7.5, 4., 1.));
+        drop(target);
+        // Just left of a chunk rounds to right on the far face of the one next to it
+        assert_eq!(Chunk::chunk_offset(Chunk::rebase(Vec3A::new(-1e-7, 4., 4.), IVec3::new(-1, 0, 0))), IVec3::ZERO);
+        // The new chunk has a full halo around it
+        for offset in World::surrounding_chunk_offsets {
+            assert!(world.chunk(IVec3::new(-1, 0, 1) + offset).is_some());
+        }
+    }
+
+    #[test]
+    fn surrounding_chunks_at_edge() {
+        let mut world = World::new();
+        world.add_particle(particle(Vec3A::new(4., 4., 4.)));
+
+        // The center chunk has its whole halo
+        let nbh = world.get_surrounding_chunks(World::chunk_id(IVec3::ZERO));
+        assert!(nbh.is_complete());
+        assert!(nbh.get(IVec3::new(1, -1, 0)).unwrap().lock().unwrap().pos == IVec3::new(8, -8, 0));
+
+        // A halo chunk is on the edge of the loaded region so some of its neighbours are missing
+        let nbh = world.get_surrounding_chunks(World::chunk_id(IVec3::ONE));
+        assert!(!nbh.is_complete());
+        assert!(nbh.center().is_some());
+        assert!(nbh.get(IVec3::ONE).is_none());
+        assert!(nbh.get(IVec3::NEG_ONE).is_some());
+        assert_eq!(nbh.iter().count(), 8);
+
+        // Nothing loaded at all
+        assert_eq!(world.get_surrounding_chunks(World::chunk_id(IVec3::splat(10))).iter().count(), 0);
+    }
+
+    #[test]
+    fn periodic_chunks_wrap() {
+        let boundary = DomainBoundary::default().with_axis(0, Wall::Periodic);
+        let mut world = World::with_boundary(IVec3::ZERO, IVec3::splat(24), boundary).unwrap();
+        // Placed past the x face, it comes back in at the other end
+        world.add_particle(particle(Vec3A::new(25., 4., 4.)));
+        assert_eq!(world.particles()[0].x, Vec3A::new(1., 4., 4.));
+
+        // No chunks get made past the periodic faces, the halo wraps around instead
+        assert!(world.chunks.keys().all(|&i| (0..3).contains(&World::chunk_coords(i).x)));
+        assert!(world.chunk(IVec3::new(2, 0, 0)).is_some());
+        assert!(world.chunk(IVec3::new(0, -1, 0)).is_some());
+        assert!(world.get_surrounding_chunks(World::chunk_id(IVec3::ZERO)).is_complete());
+        assert_eq!(world.neighbour(World::chunk_id(IVec3::ZERO), IVec3::new(-1, -1, 0)), World::chunk_id(IVec3::new(2, -1, 0)));
+
+        // Leaving through the far face lands in the first chunk
+        world.chunks.get_mut(&World::chunk_id(IVec3::ZERO)).unwrap().get_mut().unwrap().particles[0].x = Vec3A::new(-0.5, 4., 4.);
+        world.redistribute();
+        assert_eq!(world.particles()[0].x, Vec3A::new(23.5, 4., 4.));
+    }
+
+    #[test]
+    fn levels_follow_particle_speed() {
+        let mut world = World::new();
+        world.add_particle(particle(Vec3A::new(4., 4., 4.)));
+        world.add_particle(Particle { v: Vec3A::new(0., -8., 0.), ..particle(Vec3A::new(12., 4., 4.)) });
+        world.add_particle(particle(Vec3A::new(36., 4., 4.)));
+        world.pick_levels(0.4, 0.5, &[], &[]);
+
+        let level = |x: i32| world.chunk(IVec3::new(x, 0, 0)).unwrap().lock().unwrap().level;
+        // Fast chunk steps as fine as it can, its neighbour only one level coarser
+        assert_eq!(level(1), World::max_level);
+        assert_eq!(level(0), World::max_level - 1);
+        // Halo chunks step with their finest neighbour
+        assert_eq!(level(2), World::max_level);
+        // Far away nothing is moving
+        assert_eq!(level(4), 0);
+    }
+
+    #[test]
+    fn stiff_materials_step_finer() {
+        let mut world = World::new();
+        world.add_particle(Particle { v: Vec3A::new(0., -1., 0.), ..particle(Vec3A::new(4., 4., 4.)) });
+        // Not moving but waves go through it fast
+        world.add_particle(Particle { material: MaterialId(1), ..particle(Vec3A::new(36., 4., 4.)) });
+        let wave_speeds = [1., 20.];
+        assert_eq!(world.max_signal_speed(&wave_speeds), 20.);
+        world.pick_levels(0.4, 0.5, &wave_speeds, &[]);
+
+        let level = |x: i32| world.chunk(IVec3::new(x, 0, 0)).unwrap().lock().unwrap().level;
+        assert_eq!(level(0), 1);
+        assert_eq!(level(4), World::max_level);
+    }
+
+    #[test]
+    fn viscous_materials_step_finer() {
+        let mut world = World::new();
+        world.add_particle(particle(Vec3A::new(4., 4., 4.)));
+        // Nothing moves but the goo needs substeps of at most 0.15
+        world.add_particle(Particle { material: MaterialId(1), ..particle(Vec3A::new(36., 4., 4.)) });
+        let max_dts = [f32::INFINITY, 0.15];
+        assert_eq!(world.stable_dt(&max_dts), 0.15);
+        world.pick_levels(0.4, 0.5, &[], &max_dts);
+
+        let level = |x: i32| world.chunk(IVec3::new(x, 0, 0)).unwrap().lock().unwrap().level;
+        assert_eq!(level(0), 0);
+        assert_eq!(level(4), 2);
+    }
+
+    #[test]
+    fn quiet_chunks_skip_substeps() {
+        let mut world = World::new();
+        world.add_particle(Particle { v: Vec3A::new(0., -8., 0.), ..particle(Vec3A::new(4., 4., 4.)) });
+        world.add_particle(particle(Vec3A::new(36., 4., 4.)));
+        world.pick_levels(0.4, 0.5, &[], &[]);
+
+        let activity = |world: &World, x: i32| world.chunk(IVec3::new(x, 0, 0)).unwrap().lock().unwrap().activity;
+        let mut due = [0, 0];
+        for tick in 0..World::substeps {
+            world.tick = tick;
+            world.schedule_tick(0.4);
+            if activity(&world, 0) == Activity::Due {due[0] += 1}
+            if activity(&world, 4) == Activity::Due {due[1] += 1}
+            // The halo of the fast chunk is always ready for it
+            assert!(activity(&world, 1) >= Activity::Splat);
+            // While the halo of the quiet one only wakes up when it's due
+            if tick > 0 {
+                assert_eq!(activity(&world, 3), Activity::Idle);
+            }
+        }
+        assert_eq!(due, [World::substeps, 1]);
+    }
 }